@@ -1,4 +1,4 @@
-use lib::{database, Storage};
+use lib::{database, StrStorage};
 use std::{io, path::PathBuf};
 
 fn main() {
@@ -23,7 +23,7 @@ fn main() {
                     println!("Usage: get <key>");
                     continue;
                 }
-                let r = db.get(&chunks[1]);
+                let r = db.get_str(&chunks[1]);
                 match r {
                     Ok(_) => {
                         match r.unwrap() {
@@ -39,14 +39,25 @@ fn main() {
                     println!("Usage: set <key> <value>");
                     continue;
                 }
-                let r = db.set(chunks[1], chunks[2]);
+                let r = db.set_str(chunks[1], chunks[2]);
                 match r {
                     Ok(_) => println!(">> Value set!"),
                     Err(e) => println!(">> Error setting value: {}", e)
                 }
             }
+            "delete" => {
+                if chunks.len() != 2 {
+                    println!("Usage: delete <key>");
+                    continue;
+                }
+                let r = db.delete_str(chunks[1]);
+                match r {
+                    Ok(_) => println!(">> Key deleted!"),
+                    Err(e) => println!(">> Error deleting value: {}", e)
+                }
+            }
             _ => {
-                println!("Unknown command! Known commands: get <key>, set <key> <");
+                println!("Unknown command! Known commands: get <key>, set <key> <value>, delete <key>");
             }
         }
     }
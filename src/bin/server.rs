@@ -1,24 +1,26 @@
-use lib::{database::*, Storage};
+use lib::database::*;
 use log::{debug, info};
-use std::net::TcpStream;
+use std::{net::TcpStream, thread};
 use std::{net::TcpListener, path::PathBuf};
 use std::io::{prelude::*, BufReader};
 
 fn main() {
 
-    let mut db = Database::new(PathBuf::from("/tmp/zdb")).expect("Failed to create database");
+    let db = Database::new(PathBuf::from("/tmp/zdb")).expect("Failed to create database");
     let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
 
     for stream in listener.incoming() {
         let stream = stream.unwrap();
 
         debug!("Connection established!");
-        handle_connection(stream, &mut db);
+        // Each connection gets its own handle, so slow clients do not hold up the others
+        let db = db.clone();
+        thread::spawn(move || handle_connection(stream, &db));
     }
    
 }
 
-fn handle_connection(mut stream: TcpStream, db: &mut Database) {
+fn handle_connection(mut stream: TcpStream, db: &Database) {
     let mut buf_reader = BufReader::new(&mut stream);
     let http_request: Vec<_> = buf_reader.by_ref()
         .lines()
@@ -34,13 +36,13 @@ fn handle_connection(mut stream: TcpStream, db: &mut Database) {
     let (status, mut contents) = match method {
         "GET" | "HEAD" => {
             info!("GET request for key: {key}");
-            let contents = db.get(key).unwrap();
+            let contents = db.get(key.as_bytes()).unwrap();
             match contents {
                 Some(contents) => {
                     (200, contents)
                 }
                 None => {
-                    (404, String::from("Key not found!"))
+                    (404, b"Key not found!".to_vec())
                 }
             }
         }
@@ -52,22 +54,24 @@ fn handle_connection(mut stream: TcpStream, db: &mut Database) {
     
             let mut buf = vec![0u8; content_length];
             if buf_reader.read_exact(&mut buf).is_ok() {
-                match String::from_utf8(buf) {
-                    Ok(value) => {
-                        if db.set(key, value.as_str()).is_ok() {
-                            (201, value)
-                        } else {
-                            (500, String::from("Oops! Something went wrong."))
-                        }                }
-                    Err(e) => {
-                        (400, format!("Unable to read content: {e}"))
-                    }
+                if db.set(key.as_bytes(), &buf).is_ok() {
+                    (201, buf)
+                } else {
+                    (500, b"Oops! Something went wrong.".to_vec())
                 }
             } else {
-                (400, String::from("Unable to read content"))
+                (400, b"Unable to read content".to_vec())
             }
         }
-        _ =>  (405, String::from("Invalid method"))
+        "DELETE" => {
+            info!("DELETE request for key: {key}");
+            if db.delete(key.as_bytes()).is_ok() {
+                (200, b"Key deleted!".to_vec())
+            } else {
+                (500, b"Oops! Something went wrong.".to_vec())
+            }
+        }
+        _ =>  (405, b"Invalid method".to_vec())
     };
 
 
@@ -77,9 +81,10 @@ fn handle_connection(mut stream: TcpStream, db: &mut Database) {
         contents.clear();
     }
     
-    let response = format!("HTTP/1.1 {status}\r\nContent-Length: {len}\r\n\r\n{contents}");
-    stream.write_all(response.as_bytes()).unwrap();
+    let mut response = format!("HTTP/1.1 {status}\r\nContent-Length: {len}\r\n\r\n").into_bytes();
+    response.extend(contents);
+    stream.write_all(&response).unwrap();
 
-    info!("Response: {:#?}", response);
+    info!("Response: {:#?}", String::from_utf8_lossy(&response));
 
 }
\ No newline at end of file
@@ -0,0 +1,90 @@
+// Probabilistic set of keys with no false negatives, used to skip segments which cannot hold a key.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_count: u8,
+}
+
+impl BloomFilter {
+    // Sizes the filter for the keys, trading `bits_per_key` bits of space per key against the false positive rate.
+    pub fn from_hashes(hashes: &[u64], bits_per_key: usize) -> BloomFilter {
+        // ln(2) * bits per key minimizes the false positive rate
+        let hash_count = ((bits_per_key as f64) * 0.69).round().clamp(1.0, 30.0) as u8;
+        let bytes = (hashes.len() * bits_per_key).max(64).div_ceil(8);
+
+        let mut filter = BloomFilter {
+            bits: vec![0; bytes],
+            hash_count,
+        };
+        for hash in hashes {
+            for bit in filter.probes(*hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.probes(hash(key)).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut output = self.bits.to_owned();
+        output.push(self.hash_count);
+        output
+    }
+
+    pub fn decode(input: &[u8]) -> Option<BloomFilter> {
+        match input.split_last() {
+            Some((&hash_count, bits)) if !bits.is_empty() && hash_count > 0 => Some(BloomFilter {
+                bits: bits.to_vec(),
+                hash_count,
+            }),
+            _ => None,
+        }
+    }
+
+    // Derives every probe from one hash by double hashing.
+    fn probes(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let bit_count = (self.bits.len() * 8) as u64;
+        let delta = (hash >> 32) | 1;
+        (0..self.hash_count as u64).map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % bit_count) as usize)
+    }
+}
+
+// FNV-1a followed by a finalizer, so nearby keys spread across the whole filter. Persisted filters depend on it staying stable.
+pub fn hash(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let keys = (0..10_000).map(|i| format!("key_{}", i).into_bytes()).collect::<Vec<_>>();
+        let filter = BloomFilter::from_hashes(&keys.iter().map(|k| hash(k.as_slice())).collect::<Vec<_>>(), 10);
+
+        assert!(keys.iter().all(|k| filter.may_contain(k)), "Bloom filter must not have false negatives");
+
+        let false_positives = (0..10_000).filter(|i| filter.may_contain(format!("missing_{}", i).as_bytes())).count();
+        assert!(false_positives < 300, "False positive rate is too high: {} in 10000", false_positives);
+
+        let decoded = BloomFilter::decode(&filter.encode()).unwrap();
+        assert!(keys.iter().all(|k| decoded.may_contain(k)), "Decoded filter must hold every key");
+        assert_eq!(decoded.bits, filter.bits);
+        assert_eq!(decoded.hash_count, filter.hash_count);
+
+        assert!(BloomFilter::decode(&[]).is_none());
+    }
+}
@@ -0,0 +1,349 @@
+use std::{ops::Range, slice};
+
+use crate::{manifest::{max_overlap, Manifest, SegmentMeta}, options::{CompactionOptions, CompactionStrategy}};
+
+// Deepest level. Its segments are never merged any further down.
+pub const MAX_LEVEL: usize = 6;
+
+// Segments to merge into a level, taken from the level above it and the segments they overlap in that level.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Compaction {
+    pub inputs: Vec<SegmentMeta>,
+    pub level: usize,
+    // Nothing deeper overlaps the inputs, so no older value is left for a tombstone to hide
+    pub drop_tombstones: bool,
+}
+
+impl Compaction {
+    // A lone segment overlapping nothing in the next level is moved there without being rewritten.
+    pub fn is_move(&self) -> bool {
+        self.inputs.len() == 1
+    }
+}
+
+// Picks the next compaction for the live segments under the configured strategy, if any is due.
+pub fn pick_compaction(manifest: &Manifest, options: &CompactionOptions) -> Option<Compaction> {
+    match options.strategy {
+        CompactionStrategy::Leveled => pick_leveled(manifest.live(), options),
+        CompactionStrategy::SizeTiered => pick_size_tiered(manifest.live(), options),
+    }
+}
+
+// Whether level 0 passes any of the limits, which merge part of it early whatever the strategy. Each unit is a single
+// segment, or under size-tiered compaction a whole tier out of size order, so its usual layout never counts.
+fn is_over_limits(units: &[&[SegmentMeta]], unmerged_bytes: u64, options: &CompactionOptions) -> bool {
+    units.len() > options.max_segments
+        || unmerged_bytes > options.max_unmerged_bytes
+        || max_overlap(units.iter().filter_map(|unit| key_range(unit))) > options.max_read_amplification
+}
+
+// Picks the first level over its limit. Level 0 goes first, since every one of its segments may be read by a lookup,
+// and is also merged early once it is over the limits.
+fn pick_leveled(live: &[SegmentMeta], options: &CompactionOptions) -> Option<Compaction> {
+    // Level 0 ranges overlap, so all of it is merged at once to keep newer values above older ones
+    let level0 = live.iter().filter(|meta| meta.level == 0).cloned().collect::<Vec<_>>();
+    let units = level0.iter().map(slice::from_ref).collect::<Vec<_>>();
+    let unmerged_bytes = bytes(&level0) - level0.first().map_or(0, |meta| meta.size);
+    if !level0.is_empty() && (level0.len() >= options.level0_segments || is_over_limits(&units, unmerged_bytes, options)) {
+        return Some(merge_into(live, level0, 1));
+    }
+
+    for level in 1..MAX_LEVEL {
+        let segments = live.iter().filter(|meta| meta.level == level);
+        if segments.clone().map(|meta| meta.size).sum::<u64>() > level_bytes(options, level) {
+            // The oldest segment has waited longest for its keys to move down
+            let oldest = segments.min_by_key(|meta| (meta.sequence_number, &meta.smallest_key)).unwrap();
+            return Some(merge_into(live, vec![oldest.to_owned()], level + 1));
+        }
+    }
+
+    None
+}
+
+// Picks the oldest tier of level 0 segments with similar sizes, once it is long enough. Only neighbouring segments are
+// merged, so the result still sits between older and newer ones and the newest value of a key keeps winning. Once level
+// 0 is over the limits without such a tier, the neighbouring segments cheapest to merge are merged instead.
+fn pick_size_tiered(live: &[SegmentMeta], options: &CompactionOptions) -> Option<Compaction> {
+    let start = live.iter().position(|meta| meta.level == 0)?;
+    let level0 = &live[start..];
+    let tier_segments = options.tier_segments.max(2);
+
+    let tiers = tiers(level0);
+    let run = match tiers.iter().find(|tier| tier.len() >= tier_segments) {
+        Some(tier) => tier.to_owned(),
+        None => {
+            // Tiers shrink from oldest to newest while neighbours of similar size are merged. One at least as large as
+            // the tier before it is never merged with older data, so only those pile up.
+            let out_of_order = tiers.windows(2)
+                .filter(|pair| average(&level0[pair[1].to_owned()]) >= average(&level0[pair[0].to_owned()]))
+                .map(|pair| &level0[pair[1].to_owned()])
+                .collect::<Vec<_>>();
+            let unmerged_bytes = out_of_order.iter().map(|tier| bytes(tier)).sum();
+            if !is_over_limits(&out_of_order, unmerged_bytes, options) {
+                return None;
+            }
+            cheapest_run(level0, tier_segments)?
+        }
+    };
+
+    let inputs = level0[run.to_owned()].to_vec();
+    let drop_tombstones = match key_range(&inputs) {
+        Some(range) => !live[..start + run.start].iter().any(|meta| overlaps(meta, &range)),
+        None => true,
+    };
+    Some(Compaction { inputs, level: 0, drop_tombstones })
+}
+
+// Splits level 0, from oldest to newest, into tiers of neighbouring segments of similar size.
+fn tiers(level0: &[SegmentMeta]) -> Vec<Range<usize>> {
+    let mut tiers: Vec<Range<usize>> = Vec::new();
+    for (i, meta) in level0.iter().enumerate() {
+        match tiers.last_mut() {
+            Some(tier) if is_similar(meta.size, average(&level0[tier.to_owned()])) => tier.end = i + 1,
+            _ => tiers.push(i..i + 1),
+        }
+    }
+    tiers
+}
+
+// Up to a tier's worth of neighbouring segments holding the fewest bytes, so pulling level 0 back under the limits
+// never rewrites more than it has to.
+fn cheapest_run(level0: &[SegmentMeta], tier_segments: usize) -> Option<Range<usize>> {
+    let len = tier_segments.min(level0.len());
+    if len < 2 {
+        return None;
+    }
+    (0..=level0.len() - len).min_by_key(|&i| bytes(&level0[i..i + len])).map(|i| i..i + len)
+}
+
+fn bytes(metas: &[SegmentMeta]) -> u64 {
+    metas.iter().map(|meta| meta.size).sum()
+}
+
+fn average(metas: &[SegmentMeta]) -> u64 {
+    bytes(metas) / metas.len().max(1) as u64
+}
+
+// Within half again either way of the average size of a run.
+fn is_similar(size: u64, average: u64) -> bool {
+    size.saturating_mul(2) >= average && size.saturating_mul(2) <= average.saturating_mul(3)
+}
+
+fn level_bytes(options: &CompactionOptions, level: usize) -> u64 {
+    options.level_size_ratio.saturating_pow(level as u32 - 1).saturating_mul(options.level1_bytes)
+}
+
+fn merge_into(live: &[SegmentMeta], mut inputs: Vec<SegmentMeta>, level: usize) -> Compaction {
+    let overlapping = match key_range(&inputs) {
+        Some(range) => live.iter().filter(|meta| meta.level == level && overlaps(meta, &range)).cloned().collect(),
+        None => Vec::new(),
+    };
+    inputs.extend(overlapping);
+
+    let drop_tombstones = match key_range(&inputs) {
+        Some(range) => !live.iter().any(|meta| meta.level > level && overlaps(meta, &range)),
+        None => true,
+    };
+    Compaction { inputs, level, drop_tombstones }
+}
+
+// Smallest and largest key across the segments, skipping empty ones.
+fn key_range(metas: &[SegmentMeta]) -> Option<(&[u8], &[u8])> {
+    let metas = metas.iter().filter(|meta| !is_empty(meta));
+    let smallest = metas.clone().map(|meta| meta.smallest_key.as_slice()).min()?;
+    let largest = metas.map(|meta| meta.largest_key.as_slice()).max()?;
+    Some((smallest, largest))
+}
+
+fn overlaps(meta: &SegmentMeta, (smallest, largest): &(&[u8], &[u8])) -> bool {
+    !is_empty(meta) && meta.smallest_key.as_slice() <= *largest && *smallest <= meta.largest_key.as_slice()
+}
+
+fn is_empty(meta: &SegmentMeta) -> bool {
+    meta.smallest_key.is_empty() && meta.largest_key.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(file_name: &str, level: usize, smallest: &str, largest: &str) -> SegmentMeta {
+        SegmentMeta {
+            file_name: file_name.to_string(),
+            sequence_number: 1,
+            level,
+            size: 100,
+            smallest_key: smallest.as_bytes().to_vec(),
+            largest_key: largest.as_bytes().to_vec(),
+        }
+    }
+
+    fn pick(live: &[SegmentMeta], options: &CompactionOptions) -> Option<Compaction> {
+        pick_compaction(&Manifest::read_only(live.to_vec()), options)
+    }
+
+    fn names(compaction: &Compaction) -> Vec<&str> {
+        compaction.inputs.iter().map(|meta| meta.file_name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_pick_compaction() {
+        let options = CompactionOptions {
+            level0_segments: 2,
+            level1_bytes: 300,
+            level_size_ratio: 10,
+            segment_bytes: 100,
+            ..CompactionOptions::default()
+        };
+        let mut live = vec![
+            meta("deep.seg", 2, "c", "c"),
+            meta("ab.seg", 1, "a", "b"),
+            meta("cd.seg", 1, "c", "d"),
+            meta("xz.seg", 1, "x", "z"),
+            meta("new.seg", 0, "b", "c"),
+        ];
+        assert_eq!(pick(&live, &options), None);
+
+        // Only the level 1 segments overlapping level 0 are rewritten
+        live.push(meta("newer.seg", 0, "a", "a"));
+        let compaction = pick(&live, &options).unwrap();
+        assert_eq!(names(&compaction), ["new.seg", "newer.seg", "ab.seg", "cd.seg"]);
+        assert_eq!(compaction.level, 1);
+        assert!(!compaction.drop_tombstones, "Level 2 holds a key the merged range covers");
+
+        // A level over its size limit pushes its oldest segment down, moving it when nothing there overlaps
+        live.retain(|meta| meta.level != 0);
+        live.push(meta("ef.seg", 1, "e", "f"));
+        live[1].sequence_number = 0;
+        let compaction = pick(&live, &options).unwrap();
+        assert_eq!(names(&compaction), ["ab.seg"]);
+        assert_eq!(compaction.level, 2);
+        assert!(compaction.is_move());
+        assert!(compaction.drop_tombstones);
+
+        live[2].sequence_number = 0;
+        live[1].sequence_number = 1;
+        let compaction = pick(&live, &options).unwrap();
+        assert_eq!(names(&compaction), ["cd.seg", "deep.seg"]);
+        assert!(!compaction.is_move());
+    }
+
+    #[test]
+    fn test_pick_over_limits() {
+        let options = CompactionOptions { level0_segments: 4, tier_segments: 3, ..CompactionOptions::default() };
+        let mut live = (0..6).map(|i| meta(&format!("deep_{}.seg", i), 1, "a", "z")).collect::<Vec<_>>();
+        live.extend([meta("ac.seg", 0, "a", "c"), meta("bc.seg", 0, "b", "c"), meta("cd.seg", 0, "c", "d")]);
+        assert_eq!(pick(&live, &options), None, "Only level 0 counts against the limits");
+
+        // Passing any one of them merges level 0 early
+        for limited in [
+            CompactionOptions { max_segments: 2, ..options },
+            CompactionOptions { max_unmerged_bytes: 199, ..options },
+            CompactionOptions { max_read_amplification: 2, ..options },
+        ] {
+            let compaction = pick(&live, &limited).unwrap();
+            assert_eq!(names(&compaction)[..3], ["ac.seg", "bc.seg", "cd.seg"]);
+            assert_eq!(compaction.level, 1);
+        }
+
+        // Size-tiered compaction only counts tiers out of size order, so its usual layout of tiers shrinking from oldest
+        // to newest is left alone
+        let options = CompactionOptions { strategy: CompactionStrategy::SizeTiered, max_segments: 1, max_read_amplification: 1, ..options };
+        let sized = |file_name: &str, size| SegmentMeta { size, ..meta(file_name, 0, "a", "z") };
+        let mut live = vec![sized("1.seg", 900), sized("2.seg", 900), sized("3.seg", 300), sized("4.seg", 300), sized("5.seg", 100)];
+        assert_eq!(pick(&live, &options), None);
+        live.push(sized("6.seg", 900));
+        assert_eq!(pick(&live, &options), None);
+
+        // Once too many are, the neighbours cheapest to merge are merged rather than all of level 0
+        live.extend([sized("7.seg", 100), sized("8.seg", 900)]);
+        let compaction = pick(&live, &options).unwrap();
+        assert_eq!(names(&compaction), ["3.seg", "4.seg", "5.seg"]);
+        assert_eq!(compaction.level, 0);
+
+        // A tier larger than the one before it holds unmerged bytes
+        live.truncate(2);
+        live.insert(0, sized("0.seg", 100));
+        assert_eq!(pick(&live, &options), None);
+        let compaction = pick(&live, &CompactionOptions { max_unmerged_bytes: 1_000, ..options }).unwrap();
+        assert_eq!(names(&compaction), ["0.seg", "1.seg", "2.seg"]);
+    }
+
+    // Flushes segments of the given sizes, running every compaction picked after each. Returns how many times over the
+    // flushed bytes were rewritten, the most live segments there were and how many compactions ran.
+    fn simulate(options: &CompactionOptions, sizes: impl Iterator<Item = u64>) -> (f64, usize, usize) {
+        let (mut live, mut flushed, mut rewritten, mut most, mut compactions) = (Vec::new(), 0, 0, 0, 0);
+        for (i, size) in sizes.enumerate() {
+            live.push(SegmentMeta { sequence_number: i, size, ..meta(&format!("{}.seg", i), 0, "a", "z") });
+            flushed += size;
+            while let Some(compaction) = pick(&live, options) {
+                let merged = SegmentMeta {
+                    file_name: format!("{}_{}.seg", i, rewritten),
+                    sequence_number: compaction.inputs.iter().map(|meta| meta.sequence_number).max().unwrap(),
+                    level: compaction.level,
+                    size: bytes(&compaction.inputs),
+                    ..meta("", 0, "a", "z")
+                };
+                if !compaction.is_move() {
+                    rewritten += merged.size;
+                }
+                live.retain(|meta| !compaction.inputs.contains(meta));
+                live.push(merged);
+                compactions += 1;
+            }
+            most = most.max(live.len());
+        }
+        (rewritten as f64 / flushed as f64, most, compactions)
+    }
+
+    #[test]
+    fn test_write_amplification() {
+        // Each byte is rewritten about once per tier it climbs through, however large the data grows past the limits
+        let size_tiered = CompactionOptions { strategy: CompactionStrategy::SizeTiered, ..CompactionOptions::default() };
+        for flushes in [200, 1_000, 5_000] {
+            let tiers = (flushes as f64).log(4.0).ceil();
+            let (rewrites, _, _) = simulate(&size_tiered, std::iter::repeat_n(2_000, flushes));
+            assert!(rewrites <= tiers, "Bytes were rewritten {} times over {} flushes", rewrites, flushes);
+        }
+
+        // Sizes which never form a tier are merged by the limits without piling up
+        let (rewrites, most, _) = simulate(&size_tiered, [100, 10_000].into_iter().cycle().take(1_000));
+        assert!(rewrites <= 5.0, "Bytes were rewritten {} times", rewrites);
+        assert!(most <= 3 * size_tiered.max_segments, "{} segments were live at once", most);
+
+        // Leveled compaction still waits for level 0 to fill
+        let leveled = CompactionOptions::default();
+        let (_, most, compactions) = simulate(&leveled, std::iter::repeat_n(2_000, 1_000));
+        assert_eq!(compactions, 1_000 / leveled.level0_segments);
+        assert!(most <= leveled.level0_segments);
+    }
+
+    #[test]
+    fn test_pick_size_tiered() {
+        let options = CompactionOptions { strategy: CompactionStrategy::SizeTiered, tier_segments: 3, ..CompactionOptions::default() };
+        let sized = |file_name: &str, sequence_number, size| SegmentMeta { sequence_number, size, ..meta(file_name, 0, "a", "z") };
+        let mut live = vec![
+            meta("deep.seg", 1, "a", "b"),
+            sized("big.seg", 1, 10_000),
+            sized("small_1.seg", 2, 100),
+            sized("small_2.seg", 3, 120),
+        ];
+        assert_eq!(pick(&live, &options), None);
+
+        // Only neighbouring segments of similar size are merged, and they stay in level 0
+        live.push(sized("small_3.seg", 4, 90));
+        live.push(sized("big_2.seg", 5, 10_000));
+        let compaction = pick(&live, &options).unwrap();
+        assert_eq!(names(&compaction), ["small_1.seg", "small_2.seg", "small_3.seg"]);
+        assert_eq!(compaction.level, 0);
+        assert!(!compaction.drop_tombstones, "Older segments hold keys the run covers");
+
+        // The oldest run is merged first
+        let live = (1..=3).map(|i| sized(&format!("{}.seg", i), i, 100))
+            .chain((4..=6).map(|i| sized(&format!("{}.seg", i), i, 1000)))
+            .collect::<Vec<_>>();
+        let compaction = pick(&live, &options).unwrap();
+        assert_eq!(names(&compaction), ["1.seg", "2.seg", "3.seg"]);
+        assert!(compaction.drop_tombstones);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::Error;
+
+// Matches shorter than this cost more to encode than the literals they replace.
+const MIN_MATCH: usize = 4;
+const HASH_BITS: u32 = 14;
+const MAX_OFFSET: usize = 1 << 16;
+
+// Codec used to compress segment blocks. Each block records its own codec, so blocks written with
+// different codecs can coexist in the same database.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    // LZ77 style compression, replacing repeated byte runs with references back into the block.
+    #[default]
+    Lz,
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz),
+            _ => None,
+        }
+    }
+}
+
+// Compresses the block and prefixes it with the codec used. Blocks which do not shrink are stored uncompressed.
+pub(crate) fn compress(compression: Compression, input: &[u8]) -> Vec<u8> {
+    let compressed = match compression {
+        Compression::None => None,
+        Compression::Lz => Some(lz_compress(input)).filter(|c| c.len() < input.len()),
+    };
+
+    let (compression, data) = match &compressed {
+        Some(data) => (compression, data.as_slice()),
+        None => (Compression::None, input),
+    };
+
+    let mut output = Vec::with_capacity(data.len() + 1);
+    output.push(compression.id());
+    output.extend_from_slice(data);
+    output
+}
+
+pub(crate) fn decompress(input: &[u8]) -> Result<Vec<u8>, Error> {
+    let (id, data) = match input.split_first() {
+        Some(split) => split,
+        None => return Err(Error::Corruption("Block is missing its compression codec".to_string())),
+    };
+
+    match Compression::from_id(*id) {
+        Some(Compression::None) => Ok(data.to_vec()),
+        Some(Compression::Lz) => lz_decompress(data),
+        None => Err(Error::Corruption(format!("Unknown compression codec {}", id))),
+    }
+}
+
+// Writes a sequence of (literal length, literals, match length, match offset) entries.
+// The final sequence only holds literals, which tells the decoder where the block ends.
+fn lz_compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut table = vec![usize::MAX; 1 << HASH_BITS]; // Last position seen for each hash
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    while i + MIN_MATCH <= input.len() {
+        let hash = hash(&input[i..i + MIN_MATCH]);
+        let candidate = table[hash];
+        table[hash] = i;
+
+        if candidate == usize::MAX || i - candidate > MAX_OFFSET || input[candidate..candidate + MIN_MATCH] != input[i..i + MIN_MATCH] {
+            i += 1;
+            continue;
+        }
+
+        let mut len = MIN_MATCH;
+        while i + len < input.len() && input[candidate + len] == input[i + len] {
+            len += 1;
+        }
+
+        write_varint(&mut output, i - literal_start);
+        output.extend_from_slice(&input[literal_start..i]);
+        write_varint(&mut output, len);
+        write_varint(&mut output, i - candidate);
+
+        i += len;
+        literal_start = i;
+    }
+
+    write_varint(&mut output, input.len() - literal_start);
+    output.extend_from_slice(&input[literal_start..]);
+    output
+}
+
+fn lz_decompress(input: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::with_capacity(input.len() * 2);
+    let mut position = 0;
+
+    loop {
+        let literals = read_varint(input, &mut position)?;
+        let end = position.checked_add(literals).filter(|end| *end <= input.len()).ok_or_else(|| Error::Corruption("Compressed block literals run past its end".to_string()))?;
+        output.extend_from_slice(&input[position..end]);
+        position = end;
+
+        if position == input.len() {
+            return Ok(output);
+        }
+
+        let len = read_varint(input, &mut position)?;
+        let offset = read_varint(input, &mut position)?;
+        if offset == 0 || offset > output.len() {
+            return Err(Error::Corruption("Compressed block references data before its start".to_string()));
+        }
+
+        // Copy byte by byte, since a match may overlap the bytes it produces
+        let start = output.len() - offset;
+        for j in 0..len {
+            output.push(output[start + j]);
+        }
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        output.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn read_varint(input: &[u8], position: &mut usize) -> Result<usize, Error> {
+    let mut value = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = *input.get(*position).ok_or_else(|| Error::Corruption("Compressed block ends inside a length".to_string()))?;
+        *position += 1;
+        if shift >= usize::BITS {
+            return Err(Error::Corruption("Compressed block holds an oversized length".to_string()));
+        }
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_compress_decompress() {
+        let mut entropy = rand::thread_rng();
+        let random = (0..10_000).map(|_| entropy.gen()).collect::<Vec<u8>>();
+        let repetitive = b"user:0001:profile\tvalue ".repeat(400);
+        let overlapping = vec![7u8; 5_000];
+
+        for input in [Vec::new(), b"abc".to_vec(), random, repetitive.to_owned(), overlapping] {
+            for compression in [Compression::None, Compression::Lz] {
+                let compressed = compress(compression, &input);
+                assert_eq!(decompress(&compressed).unwrap(), input);
+            }
+        }
+
+        let compressed = compress(Compression::Lz, &repetitive);
+        assert_eq!(compressed[0], Compression::Lz.id());
+        assert!(compressed.len() < repetitive.len() / 10, "Repetitive block should shrink, got {} bytes", compressed.len());
+    }
+
+    #[test]
+    fn test_incompressible_falls_back() {
+        let mut entropy = rand::thread_rng();
+        let random = (0..1_000).map(|_| entropy.gen()).collect::<Vec<u8>>();
+
+        let compressed = compress(Compression::Lz, &random);
+        assert_eq!(compressed[0], Compression::None.id(), "Block which does not shrink should be stored uncompressed");
+        assert_eq!(compressed.len(), random.len() + 1);
+    }
+
+    #[test]
+    fn test_decompress_rejects_invalid() {
+        assert!(decompress(&[]).is_err());
+        assert!(decompress(&[9, 1, 2, 3]).is_err(), "Unknown codec should be rejected");
+        // One literal followed by a match reaching back further than the output
+        assert!(decompress(&[Compression::Lz.id(), 1, b'a', 4, 2]).is_err());
+        // A literal run longer than the block
+        assert!(decompress(&[Compression::Lz.id(), 10, b'a']).is_err());
+    }
+}
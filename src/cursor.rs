@@ -0,0 +1,159 @@
+use crate::Error;
+
+pub type CursorResult = Result<Option<(Vec<u8>, Vec<u8>)>, Error>;
+
+// A positionable cursor over sorted entries, where a `None` value marks a deleted key.
+pub(crate) trait EntryCursor {
+    // Positions at the first entry at or after the key.
+    fn seek(&mut self, key: &[u8]) -> Result<(), Error>;
+    // Positions at the last entry at or before the key.
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<(), Error>;
+    fn next(&mut self) -> Result<(), Error>;
+    fn prev(&mut self) -> Result<(), Error>;
+    // Returns `None` once the cursor has moved past either end.
+    fn entry(&self) -> Option<(&[u8], Option<&[u8]>)>;
+}
+
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+// A bidirectional cursor over the live key value pairs of a database.
+// While moving forward every child sits at or after the current key, and while moving in reverse at or before it.
+pub struct DatabaseCursor<'a> {
+    children: Vec<Box<dyn EntryCursor + 'a>>, // Ordered from oldest to newest
+    direction: Direction,
+    current: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> DatabaseCursor<'a> {
+    pub(crate) fn new(children: Vec<Box<dyn EntryCursor + 'a>>) -> DatabaseCursor<'a> {
+        DatabaseCursor {
+            children,
+            direction: Direction::Forward,
+            current: None,
+        }
+    }
+
+    // Positions at the first live key at or after the key and returns it.
+    pub fn seek(&mut self, key: &[u8]) -> CursorResult {
+        for child in self.children.iter_mut() {
+            child.seek(key)?;
+        }
+        self.direction = Direction::Forward;
+        self.settle_forward()
+    }
+
+    // Positions at the last live key at or before the key and returns it.
+    pub fn seek_for_prev(&mut self, key: &[u8]) -> CursorResult {
+        for child in self.children.iter_mut() {
+            child.seek_for_prev(key)?;
+        }
+        self.direction = Direction::Reverse;
+        self.settle_reverse()
+    }
+
+    // Moves to the next live key. Returns `None` if the cursor is not positioned or there are no keys after it.
+    // Not an `Iterator`, since stepping can fail and the cursor can also move backwards.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> CursorResult {
+        let key = match &self.current {
+            Some((key, _)) => key.to_owned(),
+            None => return Ok(None),
+        };
+
+        if let Direction::Reverse = self.direction {
+            // Children sit at or before the current key, so bring them all forward first
+            for child in self.children.iter_mut() {
+                child.seek(&key)?;
+            }
+            self.direction = Direction::Forward;
+        }
+
+        self.step(&key)?;
+        self.settle_forward()
+    }
+
+    // Moves to the previous live key. Returns `None` if the cursor is not positioned or there are no keys before it.
+    pub fn prev(&mut self) -> CursorResult {
+        let key = match &self.current {
+            Some((key, _)) => key.to_owned(),
+            None => return Ok(None),
+        };
+
+        if let Direction::Forward = self.direction {
+            // Children sit at or after the current key, so bring them all back first
+            for child in self.children.iter_mut() {
+                child.seek_for_prev(&key)?;
+            }
+            self.direction = Direction::Reverse;
+        }
+
+        self.step(&key)?;
+        self.settle_reverse()
+    }
+
+    // Moves every child positioned at the key one entry along in the current direction.
+    fn step(&mut self, key: &[u8]) -> Result<(), Error> {
+        for child in self.children.iter_mut() {
+            if child.entry().is_some_and(|(k, _)| k == key) {
+                match self.direction {
+                    Direction::Forward => child.next()?,
+                    Direction::Reverse => child.prev()?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn settle_forward(&mut self) -> CursorResult {
+        loop {
+            let key = self.children.iter()
+                .filter_map(|child| child.entry().map(|(k, _)| k))
+                .min()
+                .map(<[u8]>::to_vec);
+            if !self.settle(key)? {
+                return Ok(self.current.to_owned());
+            }
+        }
+    }
+
+    fn settle_reverse(&mut self) -> CursorResult {
+        loop {
+            let key = self.children.iter()
+                .filter_map(|child| child.entry().map(|(k, _)| k))
+                .max()
+                .map(<[u8]>::to_vec);
+            if !self.settle(key)? {
+                return Ok(self.current.to_owned());
+            }
+        }
+    }
+
+    // Takes the newest entry for the key as the current one. Returns true if it was deleted and the cursor must move on.
+    fn settle(&mut self, key: Option<Vec<u8>>) -> Result<bool, Error> {
+        let key = match key {
+            Some(key) => key,
+            None => {
+                self.current = None;
+                return Ok(false);
+            }
+        };
+
+        let value = self.children.iter().rev()
+            .find_map(|child| child.entry().filter(|(k, _)| *k == key).map(|(_, v)| v.map(<[u8]>::to_vec)))
+            .flatten();
+
+        match value {
+            Some(value) => {
+                self.current = Some((key, value));
+                Ok(false)
+            }
+            None => {
+                self.step(&key)?;
+                Ok(true)
+            }
+        }
+    }
+}
@@ -1,113 +1,607 @@
-use std::{fs, path::PathBuf};
-use log::warn;
+use std::{fs::{self, File, OpenOptions, TryLockError}, io, mem, ops::RangeBounds, path::{Path, PathBuf}, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}, thread::{self, JoinHandle}};
+use log::{debug, warn};
 use uuid::Uuid;
-use crate::{log_store::LogStore, memory_store::MemoryStore, segment_store::{compact, load_from_file, SegmentStore}};
-use super::{Storage, SetResult, GetResult};
+use crate::{compaction::pick_compaction, cursor::DatabaseCursor, options::{CompactionOptions, CompactionStrategy, DatabaseOptions, SyncPolicy}, log_store::{read_log, LogStore, LogStoreIterator, PendingSync}, manifest::{migrate_text_manifest, read_manifest, Manifest, SegmentMeta, VersionEdit, MANIFEST_FILE}, memory_store::{MemoryStore, LATEST}, segment_store::{compact, load_from_file, SegmentStore}, snapshot::{get_from_segments, owned_range, prefix_range, scan_range, ScanResult, Snapshot}, write_batch::WriteBatch, Error};
+use super::{Storage, SetResult, GetResult, DeleteResult};
 
-const MAX_MEMORY_USAGE: usize = 100_000;
+const LOCK_FILE: &str = "LOCK";
+// Log of the frozen memtable, kept until its segment is installed.
+const FROZEN_LOG: &str = "frozen.log";
+// Times a read-only open reads the directory again after a writer changed it partway through
+const READ_ONLY_ATTEMPTS: usize = 10;
+
+// A segment being written on a background thread
+type SegmentJob = JoinHandle<Result<SegmentStore, Error>>;
+
+// Segments being merged on a background thread into the given level
+struct CompactionJob {
+    inputs: Vec<String>,
+    level: usize,
+    handle: JoinHandle<Result<Vec<SegmentStore>, Error>>,
+}
+
+// A handle to an open database. Clones share the same database, so it can be handed to as many threads as need it.
+// Reads run side by side, while writes take turns but wait for the log to reach disk without holding up the others.
+#[derive(Clone)]
 pub struct Database {
+    state: Arc<RwLock<State>>,
+}
+
+struct State {
     directory: PathBuf,
-    memory: MemoryStore,
-    log: LogStore,
-    segments: Vec<SegmentStore>,
+    // Shared with open cursors and snapshots, which skip the versions written after them
+    memory: Arc<MemoryStore>,
+    // A full memtable, still read from while a background thread writes it out as a segment
+    immutable: Option<Arc<MemoryStore>>,
+    flush: Option<SegmentJob>,
+    compaction: Option<CompactionJob>,
+    log: Option<LogStore>, // `None` when opened read-only
+    manifest: Manifest,
+    segments: Vec<Arc<SegmentStore>>, // In the manifest's order, from oldest to newest
+    options: DatabaseOptions,
+    // Of the last write applied, carried on from the log it was replayed from
+    sequence_number: u64,
+    // A flush or compaction which failed after the write that set it off was committed, reported by the next write
+    background_error: Option<Error>,
+    // Held for as long as the database is open, keeping other writable opens out. `None` when opened read-only.
+    _lock: Option<File>,
 }
 
 impl Database {
-    pub fn new(directory: PathBuf) -> Result<Database, Box<dyn std::error::Error>> {
-        fs::create_dir_all(directory.to_owned())?;
+    pub fn new(directory: PathBuf) -> Result<Database, Error> {
+        Database::open(directory, DatabaseOptions::default())
+    }
+
+    // Opens the database only to read it, replaying the log into memory without touching anything in the directory. It
+    // takes no lock, so it can be opened alongside a writer, and sees every write the writer made up to some point while
+    // it opened, reading the directory again if the writer changed it partway through. Writes after that are not seen,
+    // and a writer which compacts meanwhile may delete segments it reads, so reopen it to catch up. `Busy` means the
+    // writer kept changing the directory for longer than the reader kept trying.
+    pub fn open_read_only(directory: PathBuf) -> Result<Database, Error> {
+        Database::open(directory, DatabaseOptions::default().read_only(true))
+    }
+
+    pub fn open(directory: PathBuf, options: DatabaseOptions) -> Result<Database, Error> {
+        // Every other file in the directory is named by the database, and some of them are removed on open
+        let log_file = options.log_file.as_str();
+        let reserved = [MANIFEST_FILE, LOCK_FILE, FROZEN_LOG].contains(&log_file) || log_file.ends_with(".seg") || log_file.ends_with(".tmp");
+        if reserved || Path::new(log_file).file_name().is_none_or(|name| name != log_file) {
+            return Err(Error::InvalidArgument(format!("{:?} cannot be used as the name of the log", log_file)));
+        }
+        let log_path = directory.join(log_file);
+        let exists = directory.join(MANIFEST_FILE).exists() || log_path.exists();
+        if !exists && (options.read_only || !options.create_if_missing) {
+            return Err(Error::NotFound(format!("No database in {}", directory.display())));
+        }
+        if exists && options.error_if_exists {
+            return Err(Error::InvalidArgument(format!("A database already exists in {}", directory.display())));
+        }
+        fs::create_dir_all(&directory)?;
+        // Taken before anything is read, so no other writer can change the directory under this one. Readers take none.
+        let lock = if options.read_only { None } else { Some(lock_directory(&directory)?) };
+
+        if !options.read_only {
+            migrate_text_manifest(&directory)?;
+        }
+        // Nothing stops a writer from flushing, compacting or rotating its log while a reader goes through the directory,
+        // so a reader which caught it partway through reads it all again
+        let mut attempts = 1;
+        let mut db = loop {
+            match State::load(&directory, &options, &log_path) {
+                Err(e) if options.read_only && attempts < READ_ONLY_ATTEMPTS && changed_while_read(&e) => attempts += 1,
+                result => break result?,
+            }
+        };
+        db._lock = lock;
+        if db.log.is_some() {
+            db.start_compaction()?;
+        }
+
+        Ok(Database { state: Arc::new(RwLock::new(db)) })
+    }
+
+    // Sets how often the write-ahead log is forced to disk. Until a write is synced it can be lost on power failure.
+    pub fn set_sync_policy(&self, sync_policy: SyncPolicy) -> SetResult {
+        let mut state = self.write_state()?;
+        state.options.sync_policy = sync_policy;
+        if let Some(log) = state.log.as_mut() {
+            log.set_sync_policy(sync_policy);
+        }
+        Ok(())
+    }
+
+    pub fn set_compaction_options(&self, compaction_options: CompactionOptions) -> SetResult {
+        self.write_state()?.options.compaction = compaction_options;
+        Ok(())
+    }
+
+    pub fn set(&self, key: &[u8], value: &[u8]) -> SetResult {
+        self.apply(|log, sequence_number| log.log_operation(sequence_number, key, Some(value)), |insert| insert(key, Some(value)))
+    }
+
+    pub fn get(&self, key: &[u8]) -> GetResult {
+        let segments = {
+            let state = self.read_state()?;
+            if let Some(value) = state.memory.get_entry(key, LATEST).or_else(|| state.immutable.as_ref().and_then(|immutable| immutable.get_entry(key, LATEST))) {
+                return Ok(value);
+            }
+            // Segments are read without the lock, so writers are not held up by the disk
+            state.segments.to_owned()
+        };
+        get_from_segments(&segments, key)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> DeleteResult {
+        self.apply(|log, sequence_number| log.log_operation(sequence_number, key, None), |insert| insert(key, None))
+    }
+
+    // Applies every operation in the batch, or none of them if the process dies before the batch is logged.
+    pub fn write(&self, batch: WriteBatch) -> SetResult {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.apply(|log, sequence_number| log.log_batch(sequence_number, &batch), |insert| {
+            for (key, value) in batch.iter() {
+                insert(key, value.as_deref());
+            }
+        })
+    }
+
+    // Iterates over the live key value pairs within the range in key order.
+    pub fn scan<'k>(&self, range: impl RangeBounds<&'k [u8]>) -> Result<impl Iterator<Item = ScanResult>, Error> {
+        let state = self.read_state()?;
+        scan_range(&state.memtables(), LATEST, &state.segments, owned_range(range))
+    }
+
+    // Iterates over the live keys starting with the prefix in key order. Segments seek straight to the block
+    // which could hold the prefix, and iteration stops at the first key past it.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<impl Iterator<Item = ScanResult>, Error> {
+        let state = self.read_state()?;
+        scan_range(&state.memtables(), LATEST, &state.segments, prefix_range(prefix))
+    }
+
+    // Returns an unpositioned cursor, which must be placed with `seek` or `seek_for_prev` before stepping. It sees the
+    // database as it was when opened, and holds no lock while it is used.
+    pub fn cursor(&self) -> Result<DatabaseCursor<'static>, Error> {
+        Ok(self.snapshot()?.cursor())
+    }
+
+    // Captures the database as it is now, so several reads can see it in the same state while writes carry on.
+    pub fn snapshot(&self) -> Result<Snapshot, Error> {
+        let state = self.read_state()?;
+        let memtables = state.immutable.iter().chain([&state.memory]).cloned().collect();
+        Ok(Snapshot::new(state.sequence_number, memtables, state.segments.to_owned()))
+    }
 
-        let paths = fs::read_dir(directory.to_owned()).unwrap();
+    // Writes the memtable out as a segment and waits for it. A background flush or compaction which failed since the
+    // last write is reported instead, and tried again by the next call.
+    pub fn flush(&self) -> SetResult {
+        let mut state = self.write_state()?;
+        if let Some(e) = state.background_error.take() {
+            return Err(e);
+        }
+        if state.log.is_none() {
+            return Err(Error::NotSupported("Database was opened read-only".to_string()));
+        }
+        if state.memory.get_memory_usage() > 0 {
+            state.freeze_memtable()?;
+        }
+        if state.finish_flush(true)? {
+            state.start_compaction()?;
+        }
+        Ok(())
+    }
+
+    // Logs and applies a write while holding the lock, then waits for the log to reach disk once other writers can go
+    // ahead, so writes which arrive together share a sync. A background flush or compaction which fails once the write
+    // is logged does not fail it, and is reported by the next write before it is logged. The log is handed the sequence
+    // number the write takes, and every key it inserts into the memtable is tagged with it.
+    fn apply(&self, log: impl FnOnce(&mut LogStore, u64) -> Result<PendingSync, Error>, apply: impl FnOnce(&mut dyn FnMut(&[u8], Option<&[u8]>))) -> SetResult {
+        let pending = {
+            let mut state = self.write_state()?;
+            if let Some(e) = state.background_error.take() {
+                return Err(e);
+            }
+            let sequence_number = state.sequence_number + 1;
+            let pending = log(state.log.as_mut().ok_or_else(|| Error::NotSupported("Database was opened read-only".to_string()))?, sequence_number)?;
+            // Snapshots are only taken under the lock, so one which holds the memtable is already counted here
+            let keep = Arc::strong_count(&state.memory) > 1;
+            apply(&mut |key, value| state.memory.insert(sequence_number, key, value, keep));
+            state.sequence_number = sequence_number;
+            if let Err(e) = state.flush_if_full() {
+                warn!("Failed to flush or compact in the background: {}", e);
+                state.background_error = Some(e);
+            }
+            pending
+        };
+        pending.wait()
+    }
+
+    // A writer which panicked may have left the database half changed, so nobody is let near it again.
+    fn read_state(&self) -> Result<RwLockReadGuard<'_, State>, Error> {
+        self.state.read().map_err(|_| Error::Internal("Database lock was poisoned by a panicked writer".to_string()))
+    }
+
+    fn write_state(&self) -> Result<RwLockWriteGuard<'_, State>, Error> {
+        self.state.write().map_err(|_| Error::Internal("Database lock was poisoned by a panicked writer".to_string()))
+    }
+}
+
+impl State {
+    // Reads the segments the manifest lists and replays the logs. Opened writable, leftovers of incomplete writes are
+    // removed and a frozen memtable is written out.
+    fn load(directory: &Path, options: &DatabaseOptions, log_path: &Path) -> Result<State, Error> {
+        let read = read_manifest(directory)?;
+        let (live, last_sequence) = match read.clone() {
+            Some((live, last_sequence)) => (Some(live), last_sequence),
+            None => (None, 0),
+        };
+
+        let is_live = |file_name: &str| live.as_ref().is_none_or(|live| live.iter().any(|meta| meta.file_name == file_name));
         let mut segments = Vec::new();
 
-        for path in paths {
-            let path = path.unwrap();
-            if path.file_name().as_os_str().to_str().unwrap().ends_with(".seg") {
-                segments.push(load_from_file(path.path())?);
+        for path in fs::read_dir(directory)? {
+            let path = path?.path();
+            let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_owned();
+            if file_name.ends_with(".seg") && is_live(&file_name) {
+                segments.push(load_from_file(path)?);
+            } else if options.read_only {
+                // Leftovers are skipped, and only cleaned up by a writable open
+            } else if file_name.ends_with(".tmp") {
+                warn!("Removing {} left behind by an incomplete write", file_name);
+                fs::remove_file(&path)?;
+            } else if file_name.ends_with(".seg") {
+                // Its flush or compaction never completed, so what it holds is still in the log or older segments
+                warn!("Removing segment {} which is not in the manifest", file_name);
+                fs::remove_file(&path)?;
             }
         }
 
-        segments.sort_by(|a, b| a.get_sequence_number().cmp(&b.get_sequence_number()));
-        if segments.len() > 1 {
-            let new_segment: SegmentStore = compact(directory.join(format!("{}.seg", Uuid::new_v4().to_string())), &mut segments)?;
-            
-            segments.iter().map(|s| s.delete())
-            .filter(Result::is_err)
-            .for_each(|r| warn!("Failed to delete segment: {}", r.err().unwrap()));
-            segments = vec![new_segment];            
+        for meta in live.iter().flatten() {
+            if !segments.iter().any(|s| s.get_file_name() == meta.file_name) {
+                return Err(Error::NotFound(format!("Segment {} listed in the manifest is missing", meta.file_name)));
+            }
         }
 
-        let mut db = Database {
-            memory: MemoryStore::new(),
-            log: LogStore::init(directory.join("write.log")),
-            segments: segments,
-            directory: directory,
+        // Start a fresh manifest, so edits do not pile up across restarts, unless opened read-only. Segments from before
+        // the manifest are all taken to be in level 0.
+        let level_of = |file_name: &str| live.iter().flatten().find(|meta| meta.file_name == file_name).map_or(0, |meta| meta.level);
+        let metas = segments.iter().map(|s| SegmentMeta::of(s, level_of(s.get_file_name()))).collect::<Result<Vec<_>, _>>()?;
+        let (manifest, log) = if options.read_only {
+            (Manifest::read_only(metas), None)
+        } else {
+            let mut log = LogStore::init(log_path.to_owned())?;
+            log.set_sync_policy(options.sync_policy);
+            (Manifest::create(directory, metas, last_sequence)?, Some(log))
         };
 
-        let entries = match db.log.iter() {
-            Ok(entries) => entries,
-            Err(e) => return Err(e.into())
+        let mut db = State {
+            memory: Arc::new(MemoryStore::new()),
+            immutable: None,
+            flush: None,
+            compaction: None,
+            log,
+            manifest,
+            segments: segments.into_iter().map(Arc::new).collect(),
+            directory: directory.to_owned(),
+            options: options.to_owned(),
+            // Writes already flushed are no longer in any log, so numbering carries on from the manifest
+            sequence_number: last_sequence,
+            background_error: None,
+            _lock: None,
         };
-        
-        for (k,v) in entries {
-            match db.memory.set(&k, &v) {
-                Err(e) => return Err(e),
-                _ => {}
+        db.order_segments();
+
+        let frozen_path = db.directory.join(FROZEN_LOG);
+        if db.log.is_none() {
+            db.replay_read_only(log_path, &frozen_path, read)?;
+            return Ok(db);
+        }
+
+        // A background flush never completed, so write out the memtable it froze before replaying newer writes
+        if frozen_path.exists() {
+            let mut frozen = LogStore::init(frozen_path.to_owned())?;
+            frozen.recover()?;
+            let mut memory = MemoryStore::new();
+            db.sequence_number = db.sequence_number.max(replay(frozen.iter()?, &mut memory)?);
+            if memory.get_memory_usage() > 0 {
+                db.immutable = Some(Arc::new(memory));
+                db.finish_flush(true)?;
+            } else {
+                fs::remove_file(&frozen_path)?;
             }
         }
 
+        let log = db.log.as_mut().unwrap();
+        log.recover()?;
+        let mut memory = MemoryStore::new();
+        db.sequence_number = db.sequence_number.max(replay(log.iter()?, &mut memory)?);
+        db.memory = Arc::new(memory);
         Ok(db)
     }
-}
 
-impl Storage for Database {
-    fn set(&mut self, key: &str, value: &str) -> SetResult {
-        self.log.set(key, value)?;
-        self.memory.set(key, value)?;
-        if self.memory.get_memory_usage() > MAX_MEMORY_USAGE {
-            self.segments.push(SegmentStore::create_from_iterator(
-                self.directory.join(self.directory.join(format!("{}.seg", Uuid::new_v4().to_string()))),
-                self.segments.iter().map(|s| s.get_sequence_number()).max().unwrap_or(0) + 1,
-                self.memory.iter().map(|(k, v)| (k.to_owned(), v.to_owned()))
-            ).unwrap());
-            self.memory = MemoryStore::new();
-            self.log.flush()?;
+    // Reads the log before the frozen one. A rotation in between leaves the frozen log holding the writes just read, and
+    // a flush or compaction finishing meanwhile changes the manifest, so either is reported as `Busy` for the open to
+    // try again. Otherwise the reader sees every write up to some point, with none missing before it.
+    fn replay_read_only(&mut self, log_path: &Path, frozen_path: &Path, read: Option<(Vec<SegmentMeta>, u64)>) -> SetResult {
+        let mut memory = MemoryStore::new();
+        let log_sequence = match log_path.exists() {
+            true => replay(read_log(log_path)?, &mut memory)?,
+            false => 0,
+        };
+        let mut frozen = MemoryStore::new();
+        let frozen_sequence = match frozen_path.exists() {
+            true => replay(read_log(frozen_path)?, &mut frozen)?,
+            false => 0,
+        };
+
+        if memory.get_memory_usage() > 0 && log_sequence <= frozen_sequence {
+            return Err(Error::Busy(format!("Log in {} was rotated while it was read", self.directory.display())));
+        }
+        if read_manifest(&self.directory)? != read {
+            return Err(Error::Busy(format!("Manifest in {} changed while it was read", self.directory.display())));
+        }
+        self.memory = Arc::new(memory);
+        if frozen.get_memory_usage() > 0 {
+            self.immutable = Some(Arc::new(frozen));
+        }
+        self.sequence_number = self.sequence_number.max(log_sequence).max(frozen_sequence);
+        Ok(())
+    }
+
+    // Ordered from oldest to newest.
+    fn memtables(&self) -> Vec<&MemoryStore> {
+        self.immutable.iter().chain([&self.memory]).map(Arc::as_ref).collect()
+    }
+
+    // Freezes a full memtable and hands it to a background thread, so the writer which filled it does not wait on the write.
+    fn flush_if_full(&mut self) -> SetResult {
+        if self.finish_flush(false)? || self.finish_compaction(false)? {
+            self.start_compaction()?;
+        }
+        if self.memory.get_memory_usage() <= self.options.memtable_bytes {
+            return Ok(());
+        }
+        self.freeze_memtable()
+    }
+
+    fn freeze_memtable(&mut self) -> SetResult {
+        // Only one memtable is frozen at a time, so wait for the last one to be written out
+        if self.finish_flush(true)? {
+            self.start_compaction()?;
+        }
+        if let Some(log) = self.log.as_mut() {
+            log.rotate(&self.directory.join(FROZEN_LOG))?;
         }
+        self.immutable = Some(mem::replace(&mut self.memory, Arc::new(MemoryStore::new())));
+        self.start_flush();
 
         Ok(())
     }
 
-    fn get(&self, key: &str) -> GetResult {
-        match self.memory.get(key)? {
-            Some(value) => Ok(Some(value)),
-            None => {
-                for segment in self.segments.iter().rev() {
-                    match segment.get(key) {
-                        Ok(Some(value)) => return Ok(Some(value)),
-                        _ => {}
-                    }
-                }
-                Ok(None)
+    fn start_flush(&mut self) {
+        let immutable = match &self.immutable {
+            Some(immutable) => immutable.to_owned(),
+            None => return,
+        };
+        let file_path = self.directory.join(format!("{}.seg", Uuid::new_v4()));
+        let sequence_number = self.manifest.live().iter().map(|meta| meta.sequence_number).max().unwrap_or(0) + 1;
+        let segment_options = self.options.segment;
+
+        self.flush = Some(thread::spawn(move || {
+            immutable.with_latest(|entries| {
+                SegmentStore::create_from_iterator(file_path, sequence_number, segment_options, entries)
+            })
+        }));
+    }
+
+    // Installs the segment written from the frozen memtable once it is done, or waits for it when asked to. A failed
+    // flush keeps the frozen memtable and its log, and is retried the next time it is waited for. Returns whether a
+    // segment was installed.
+    fn finish_flush(&mut self, wait: bool) -> Result<bool, Error> {
+        if self.immutable.is_none() {
+            return Ok(false);
+        }
+        if self.flush.is_none() && wait {
+            self.start_flush();
+        }
+        let flush = match self.flush.take() {
+            Some(flush) if wait || flush.is_finished() => flush,
+            flush => {
+                self.flush = flush;
+                return Ok(false);
+            }
+        };
+
+        let segment = flush.join().map_err(|_| Error::Internal("Background flush panicked".to_string()))??;
+
+        // The frozen log is only removed once the segment holding its writes is listed in the manifest
+        self.manifest.apply(VersionEdit {
+            added: vec![SegmentMeta::of(&segment, 0)?],
+            removed: Vec::new(),
+            last_sequence: Some(self.sequence_number),
+        })?;
+        self.segments.push(Arc::new(segment));
+        self.immutable = None;
+        let frozen_path = self.directory.join(FROZEN_LOG);
+        if frozen_path.exists() {
+            fs::remove_file(&frozen_path)?;
+        }
+
+        Ok(true)
+    }
+
+    // Merges segments from a level over its limit into the next on a background thread. Reads keep using the old
+    // segments until the merged ones are installed.
+    fn start_compaction(&mut self) -> SetResult {
+        while self.compaction.is_none() {
+            let compaction = match pick_compaction(&self.manifest, &self.options.compaction) {
+                Some(compaction) => compaction,
+                None => return Ok(()),
+            };
+
+            if compaction.is_move() {
+                let meta = &compaction.inputs[0];
+                debug!("Moving segment {} to level {}", meta.file_name, compaction.level);
+                let moved = SegmentMeta { level: compaction.level, ..meta.to_owned() };
+                self.manifest.apply(VersionEdit { added: vec![moved], removed: vec![meta.file_name.to_owned()], ..VersionEdit::default() })?;
+                self.order_segments();
+                continue;
+            }
+
+            let inputs = compaction.inputs.iter().map(|meta| meta.file_name.to_owned()).collect::<Vec<_>>();
+            let mut segments = self.segments.iter()
+                .filter(|s| inputs.iter().any(|name| name == s.get_file_name()))
+                .cloned()
+                .collect::<Vec<_>>();
+            let directory = self.directory.to_owned();
+            let segment_options = self.options.segment;
+            // A size tier is only worth anything as one segment
+            let segment_bytes = match self.options.compaction.strategy {
+                CompactionStrategy::Leveled => self.options.compaction.segment_bytes,
+                CompactionStrategy::SizeTiered => u64::MAX,
+            };
+            let drop_tombstones = compaction.drop_tombstones;
+
+            debug!("Compacting {} segments into level {} in the background", segments.len(), compaction.level);
+            self.compaction = Some(CompactionJob {
+                inputs,
+                level: compaction.level,
+                handle: thread::spawn(move || {
+                    compact(&directory, &mut segments, drop_tombstones, segment_options, segment_bytes)
+                }),
+            });
+        }
+
+        Ok(())
+    }
+
+    // Swaps the merged segments in for the ones they were built from once they are done, or waits for them when asked
+    // to. Returns whether segments were installed.
+    fn finish_compaction(&mut self, wait: bool) -> Result<bool, Error> {
+        let job = match self.compaction.take() {
+            Some(job) if wait || job.handle.is_finished() => job,
+            job => {
+                self.compaction = job;
+                return Ok(false);
             }
+        };
+
+        let segments = job.handle.join().map_err(|_| Error::Internal("Background compaction panicked".to_string()))??;
+
+        // Segments flushed while the compaction ran stay in level 0, above everything it merged
+        let added = segments.iter().map(|s| SegmentMeta::of(s, job.level)).collect::<Result<Vec<_>, _>>()?;
+        self.manifest.apply(VersionEdit { added, removed: job.inputs.to_owned(), ..VersionEdit::default() })?;
+        let (old_segments, mut kept): (Vec<_>, Vec<_>) = mem::take(&mut self.segments).into_iter()
+            .partition(|s| job.inputs.iter().any(|name| name == s.get_file_name()));
+        kept.extend(segments.into_iter().map(Arc::new));
+        self.segments = kept;
+        self.order_segments();
+
+        // Cursors and reads may still be using the old segments, so their files go once the last of them is done
+        old_segments.iter().for_each(|s| s.delete_when_unused());
+
+        Ok(true)
+    }
+
+    fn order_segments(&mut self) {
+        let live = self.manifest.live();
+        self.segments.sort_by_key(|s| live.iter().position(|meta| meta.file_name == s.get_file_name()));
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        if self.options.read_only {
+            return;
+        }
+        if let Err(e) = self.finish_flush(true) {
+            warn!("Failed to flush the frozen memtable, it will be recovered from its log: {}", e);
+        }
+        if let Err(e) = self.finish_compaction(true) {
+            warn!("Failed to finish compacting segments: {}", e);
+        }
+    }
+}
+
+// Locks the directory against any other writable open, whether from this process or another. The operating system lets
+// go of the lock once the file is closed, even if the process dies, and the empty file is left for the next open.
+fn lock_directory(directory: &Path) -> Result<File, Error> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(directory.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(Error::Busy(format!("Database in {} is already open for writing", directory.display()))),
+        Err(TryLockError::Error(e)) => Err(Error::Io(e)),
+    }
+}
+
+// Returns the sequence number of the last write replayed.
+// Whether an error could come from a writer changing the directory while it was read
+fn changed_while_read(error: &Error) -> bool {
+    match error {
+        Error::Busy(_) | Error::NotFound(_) => true,
+        Error::Io(e) => e.kind() == io::ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
+fn replay(mut log: LogStoreIterator, memory: &mut MemoryStore) -> Result<u64, Error> {
+    for entry in log.by_ref() {
+        let (k, v) = entry?;
+        match v {
+            Some(v) => memory.set(&k, &v)?,
+            None => memory.delete(&k)?,
         }
     }
+    Ok(log.sequence_number())
+}
+
+impl Storage for Database {
+    fn set(&mut self, key: &[u8], value: &[u8]) -> SetResult {
+        Database::set(self, key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> GetResult {
+        Database::get(self, key)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> DeleteResult {
+        Database::delete(self, key)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{compression::Compression, StrStorage};
+    use std::{fs::OpenOptions, io::Write, ops::Bound};
+
+    // Writes `{prefix}_{i:04}` keys until the memtable is frozen, so whatever was written before them is on its way to a
+    // segment. Returns how many keys were written.
+    fn fill_memtable(db: &Database, prefix: &str, value: &[u8]) -> usize {
+        let mut i = 0;
+        while db.read_state().unwrap().immutable.is_none() {
+            db.set(format!("{}_{:04}", prefix, i).as_bytes(), value).unwrap();
+            i += 1;
+        }
+        i
+    }
 
     #[test]
     fn test_set_get() {
-        let mut db = Database::new(PathBuf::from("/tmp/zdb_test_database")).expect("Failed to create database");
+        let directory = PathBuf::from("/tmp/zdb_test_database");
+        let _ = fs::remove_dir_all(&directory);
+        let mut db = Database::new(directory.to_owned()).expect("Failed to create database");
 
         let k = "key";
         let v = "value";
         
-        match db.set(k, v) {
+        match db.set_str(k, v) {
             Ok(_) => {
-                match db.get("key") {
+                match db.get_str("key") {
                     Ok(_) => {
-                        match db.get("key").unwrap() {
+                        match db.get_str("key").unwrap() {
                             Some(value) => {
                                 assert_eq!(value, v);
                             }
@@ -127,7 +621,921 @@ mod tests {
             }
         }
 
-        
+        drop(db);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_delete() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_delete");
+        let _ = fs::remove_dir_all(&directory);
+        let mut db = Database::new(directory.to_owned()).expect("Failed to create database");
+
+        db.set_str("deleted", "value").unwrap();
+        db.set_str("kept", "value").unwrap();
+
+        // Push both keys out of memory and into a segment
+        fill_memtable(&db, "filler", b"value");
+        db.write_state().unwrap().finish_flush(true).unwrap();
+        assert!(!db.read_state().unwrap().segments.is_empty(), "Memory should have been flushed into a segment");
+
+        db.delete_str("deleted").unwrap();
+        assert_eq!(db.get_str("deleted").unwrap(), None, "Tombstone in memory should hide the segment value");
+        assert_eq!(db.get_str("kept").unwrap(), Some("value".to_string()));
+
+        // Replaying the log restores the tombstone
+        drop(db);
+        let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+        assert_eq!(db.get_str("deleted").unwrap(), None, "Tombstone should be replayed from the log");
+        assert_eq!(db.get_str("kept").unwrap(), Some("value".to_string()));
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_scan() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_scan");
+        let _ = fs::remove_dir_all(&directory);
+        let mut db = Database::new(directory.to_owned()).expect("Failed to create database");
+
+        db.set_str("a", "old").unwrap();
+        db.set_str("b", "old").unwrap();
+        db.set_str("c", "old").unwrap();
+
+        // Push the keys out of memory and into a segment
+        let fillers = fill_memtable(&db, "filler", b"value");
+        db.write_state().unwrap().finish_flush(true).unwrap();
+        assert!(!db.read_state().unwrap().segments.is_empty(), "Memory should have been flushed into a segment");
+
+        db.set_str("a", "new").unwrap();
+        db.delete_str("b").unwrap();
+        db.set_str("d", "new").unwrap();
+
+        let scanned = db.scan(b"a".as_slice()..b"e".as_slice()).unwrap().map(Result::unwrap).filter(|(k, _)| !k.starts_with(b"filler_")).collect::<Vec<_>>();
+        assert_eq!(scanned, vec![
+            (b"a".to_vec(), b"new".to_vec()),
+            (b"c".to_vec(), b"old".to_vec()),
+            (b"d".to_vec(), b"new".to_vec()),
+        ]);
+
+        let scanned = db.scan(b"b".as_slice()..=b"c".as_slice()).unwrap().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(scanned, vec![(b"c".to_vec(), b"old".to_vec())]);
+
+        let keys = db.scan(..).unwrap().map(Result::unwrap).map(|(k, _)| k).collect::<Vec<_>>();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted, "Scan should return keys in order");
+        assert_eq!(keys.len(), 3 + fillers);
+
+        assert!(db.scan(b"c".as_slice()..b"a".as_slice()).is_err(), "Reversed range should be rejected");
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_scan_prefix");
+        let _ = fs::remove_dir_all(&directory);
+        let mut db = Database::new(directory.to_owned()).expect("Failed to create database");
+
+        // Spread namespaced keys across many blocks of a segment
+        let value = "x".repeat(100);
+        for user in 0..1_200 {
+            for field in ["email", "name", "profile"] {
+                db.set_str(&format!("user:{:04}:{}", user, field), &value).unwrap();
+            }
+        }
+        db.write_state().unwrap().finish_flush(true).unwrap();
+        assert!(!db.read_state().unwrap().segments.is_empty(), "Memory should have been flushed into a segment");
+
+        db.set_str("user:0123:address", "new").unwrap();
+        db.delete_str("user:0123:name").unwrap();
+        db.set_str("user:01230:name", "other").unwrap();
+
+        let keys = db.scan_prefix(b"user:0123:").unwrap().map(Result::unwrap).map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys, vec![
+            b"user:0123:address".to_vec(),
+            b"user:0123:email".to_vec(),
+            b"user:0123:profile".to_vec(),
+        ]);
+
+        assert_eq!(db.scan_prefix(b"user:").unwrap().count(), 3 * 1_200 + 1);
+        assert_eq!(db.scan_prefix(b"missing:").unwrap().count(), 0);
+
+        // Trailing 0xff bytes cannot be incremented, so the range ends past the byte before them
+        for key in [&b"a\xff"[..], b"a\xff\x00", b"a\xff\xff", b"b", b"\xff", b"\xff\xff\x01"] {
+            db.set(key, b"1").unwrap();
+        }
+        let keys = |prefix: &[u8]| db.scan_prefix(prefix).unwrap().map(Result::unwrap).map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys(b"a\xff"), [&b"a\xff"[..], b"a\xff\x00", b"a\xff\xff"]);
+        assert_eq!(keys(b"\xff\xff"), [&b"\xff\xff\x01"[..]]);
+        assert_eq!(prefix_range(b"a\xff"), (Bound::Included(b"a\xff".to_vec()), Bound::Excluded(b"b".to_vec())));
+        assert_eq!(prefix_range(b"\xff\xff").1, Bound::Unbounded);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_cursor() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_cursor");
+        let _ = fs::remove_dir_all(&directory);
+        let mut db = Database::new(directory.to_owned()).expect("Failed to create database");
+
+        let value = "x".repeat(100);
+        for i in 0..1_000 {
+            db.set_str(&format!("item:{:04}", i), &value).unwrap();
+        }
+        db.write_state().unwrap().finish_flush(true).unwrap();
+        assert!(!db.read_state().unwrap().segments.is_empty(), "Memory should have been flushed into a segment");
+
+        // Shadow segment entries from memory
+        db.delete_str("item:0498").unwrap();
+        db.delete_str("item:0499").unwrap();
+        db.set_str("item:0497", "new").unwrap();
+
+        // The latest three items before item:0500
+        let mut cursor = db.cursor().unwrap();
+        let mut latest = vec![cursor.seek_for_prev(b"item:0500").unwrap().unwrap()];
+        assert_eq!(latest[0].0, b"item:0500");
+        for _ in 0..3 {
+            latest.push(cursor.prev().unwrap().unwrap());
+        }
+        assert_eq!(latest.iter().map(|(k, _)| k.as_slice()).collect::<Vec<_>>(), vec![b"item:0500", b"item:0497", b"item:0496", b"item:0495"]);
+        assert_eq!(latest[1].1, b"new");
+
+        // Switching direction walks back over the same keys
+        assert_eq!(cursor.next().unwrap().unwrap().0, b"item:0496");
+        assert_eq!(cursor.next().unwrap().unwrap().0, b"item:0497");
+        assert_eq!(cursor.next().unwrap().unwrap().0, b"item:0500");
+
+        assert_eq!(cursor.seek(b"item:0498").unwrap().unwrap().0, b"item:0500");
+        assert_eq!(cursor.seek(b"item:0999").unwrap().unwrap().0, b"item:0999");
+        assert_eq!(cursor.next().unwrap(), None);
+        assert_eq!(cursor.seek_for_prev(b"item").unwrap(), None);
+
+        // Walking the whole database backwards matches a forward scan
+        let mut keys = vec![cursor.seek_for_prev(b"item:9999").unwrap().unwrap().0];
+        while let Some((key, _)) = cursor.prev().unwrap() {
+            keys.push(key);
+        }
+        keys.reverse();
+        assert_eq!(keys, db.scan(..).unwrap().map(Result::unwrap).map(|(k, _)| k).collect::<Vec<_>>());
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_write_batch");
+        let _ = fs::remove_dir_all(&directory);
+        let mut db = Database::new(directory.to_owned()).expect("Failed to create database");
+
+        db.set_str("from", "100").unwrap();
+        db.set_str("to", "0").unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.set(b"from", b"50").set(b"to", b"50").delete(b"pending");
+        db.write(batch).unwrap();
+
+        assert_eq!(db.get_str("from").unwrap(), Some("50".to_string()));
+        assert_eq!(db.get_str("to").unwrap(), Some("50".to_string()));
+
+        // Replaying the log applies the batch again
+        drop(db);
+        let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+        assert_eq!(db.get_str("from").unwrap(), Some("50".to_string()));
+        assert_eq!(db.get_str("to").unwrap(), Some("50".to_string()));
+        assert_eq!(db.get_str("pending").unwrap(), None);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_sync_policy");
+        let _ = fs::remove_dir_all(&directory);
+
+        for sync_policy in [SyncPolicy::Always, SyncPolicy::Bytes(64), SyncPolicy::Interval(std::time::Duration::from_millis(5)), SyncPolicy::Never] {
+            let mut db = Database::new(directory.to_owned()).expect("Failed to create database");
+            db.set_sync_policy(sync_policy).unwrap();
+            for i in 0..20 {
+                db.set_str(&format!("{:?}_{}", sync_policy, i), "value").unwrap();
+            }
+            drop(db);
+
+            let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+            for i in 0..20 {
+                assert_eq!(db.get_str(&format!("{:?}_{}", sync_policy, i)).unwrap(), Some("value".to_string()));
+            }
+        }
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_background_flush() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_background_flush");
+        let _ = fs::remove_dir_all(&directory);
+        let db = Database::new(directory.to_owned()).expect("Failed to create database");
+
+        let value = "value ".repeat(20);
+        let i = fill_memtable(&db, "key", value.as_bytes());
+        assert_eq!(db.read_state().unwrap().memory.get_memory_usage(), 0, "Writes should go to a fresh memtable");
+        assert!(directory.join(FROZEN_LOG).exists());
+
+        // The frozen memtable is still read from while it is written out
+        assert_eq!(db.get(b"key_0000").unwrap(), Some(value.into_bytes()));
+        assert_eq!(db.scan(..).unwrap().count(), i);
+        let mut cursor = db.cursor().unwrap();
+        assert_eq!(cursor.seek(b"key_0001").unwrap().map(|(k, _)| k), Some(b"key_0001".to_vec()));
+        drop(cursor);
+
+        // The process dies after the segment is written but before it is installed
+        let flush = db.write_state().unwrap().flush.take().unwrap();
+        flush.join().unwrap().expect("Failed to write segment");
+        // Like the process dying, which lets go of the directory lock without finishing anything else
+        let lock = db.write_state().unwrap()._lock.take();
+        mem::forget(db);
+        drop(lock);
+
+        let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+        assert!(!directory.join(FROZEN_LOG).exists(), "Frozen log should be retired once recovered");
+        assert_eq!(db.read_state().unwrap().segments.len(), 1);
+        assert_eq!(db.scan(..).unwrap().count(), i);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_failed_flush() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_failed_flush");
+        let _ = fs::remove_dir_all(&directory);
+        let mut db = Database::open(directory.to_owned(), DatabaseOptions::default().memtable_bytes(100)).expect("Failed to create database");
+        db.set_str("a", "1").unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.read_state().unwrap().segments.len(), 1);
+        assert!(db.read_state().unwrap().immutable.is_none());
+
+        // The log stays open, so the write fills the memtable but its flush cannot be started
+        fs::remove_dir_all(&directory).unwrap();
+        db.set_str("b", &"2".repeat(200)).expect("Committed write should not fail with its flush");
+        assert_eq!(db.get_str("b").unwrap(), Some("2".repeat(200)));
+
+        // The next write is refused before it is logged
+        assert!(matches!(db.set_str("c", "3"), Err(Error::Io(_))));
+        assert_eq!(db.get_str("c").unwrap(), None);
+
+        // Flushing by hand tries again, and reports that it still fails
+        assert!(db.flush().is_err());
+        drop(db);
+    }
+
+    #[test]
+    fn test_background_compaction() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_background_compaction");
+        let _ = fs::remove_dir_all(&directory);
+        let mut db = Database::new(directory.to_owned()).expect("Failed to create database");
+        db.set_compaction_options(CompactionOptions {
+            level0_segments: 2,
+            level1_bytes: 20_000,
+            level_size_ratio: 4,
+            segment_bytes: 20_000,
+            ..CompactionOptions::default()
+        }).unwrap();
+
+        // Keep overwriting the same keys while segments are pushed down the levels
+        let mut expected = std::collections::BTreeMap::new();
+        for i in 0..20_000 {
+            let (key, value) = (format!("key_{:04}", (i * 7) % 3000), format!("{} {}", i, "value ".repeat(20)));
+            db.set_str(&key, &value).unwrap();
+            expected.insert(key, value);
+
+            // Reads keep working while segments are merged
+            if db.read_state().unwrap().compaction.is_some() && i % 100 == 0 {
+                assert_eq!(db.get_str("key_0007").unwrap().as_ref(), expected.get("key_0007"));
+            }
+        }
+        db.delete(b"key_0000").unwrap();
+        expected.remove("key_0000");
+
+        db.write_state().unwrap().finish_flush(true).unwrap();
+        while db.write_state().unwrap().finish_compaction(true).unwrap() {
+            db.write_state().unwrap().start_compaction().unwrap();
+        }
+
+        let live = db.read_state().unwrap().manifest.live().to_vec();
+        assert!(live.iter().filter(|meta| meta.level == 0).count() < 2, "Level 0 should have been merged down");
+        assert!(live.iter().any(|meta| meta.level >= 2), "Segments should have moved past level 1: {:?}", live);
+        for level in 1..=crate::compaction::MAX_LEVEL {
+            let ranges = live.iter().filter(|meta| meta.level == level).map(|meta| (&meta.smallest_key, &meta.largest_key));
+            let mut ranges = ranges.collect::<Vec<_>>();
+            ranges.sort();
+            assert!(ranges.windows(2).all(|pair| pair[0].1 < pair[1].0), "Level {} ranges should be disjoint: {:?}", level, ranges);
+        }
+
+        let check = |db: &Database| {
+            assert_eq!(db.get(b"key_0000").unwrap(), None);
+            for (key, value) in expected.iter() {
+                assert_eq!(db.get_str(key).unwrap().as_ref(), Some(value));
+            }
+            assert_eq!(db.scan(..).unwrap().count(), expected.len());
+        };
+        check(&db);
+
+        // Only the live segments are left on disk
+        let mut files = fs::read_dir(&directory).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".seg"))
+            .collect::<Vec<_>>();
+        files.sort();
+        let mut live = db.read_state().unwrap().manifest.live().iter().map(|meta| meta.file_name.to_owned()).collect::<Vec<_>>();
+        live.sort();
+        assert_eq!(files, live);
+
+        drop(db);
+        let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+        check(&db);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_size_tiered_compaction() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_size_tiered_compaction");
+        let _ = fs::remove_dir_all(&directory);
+        let mut db = Database::new(directory.to_owned()).expect("Failed to create database");
+        db.set_compaction_options(CompactionOptions {
+            strategy: CompactionStrategy::SizeTiered,
+            tier_segments: 3,
+            ..CompactionOptions::default()
+        }).unwrap();
+
+        // Newer flushes overwrite keys held by older ones, so merged tiers must keep the newest value
+        let mut expected = std::collections::BTreeMap::new();
+        let mut flushes = 0;
+        for i in 0..12_000 {
+            let memory_usage = db.read_state().unwrap().memory.get_memory_usage();
+            let (key, value) = (format!("key_{:04}", (i * 7) % 2000), format!("{} {}", i, "value ".repeat(20)));
+            db.set_str(&key, &value).unwrap();
+            expected.insert(key, value);
+            if db.read_state().unwrap().memory.get_memory_usage() < memory_usage {
+                flushes += 1;
+            }
+        }
+        db.write_state().unwrap().finish_flush(true).unwrap();
+        db.write_state().unwrap().start_compaction().unwrap();
+        while db.write_state().unwrap().finish_compaction(true).unwrap() {
+            db.write_state().unwrap().start_compaction().unwrap();
+        }
+
+        let live = db.read_state().unwrap().manifest.live().to_vec();
+        assert!(live.len() < flushes, "Tiers should have been merged: {:?}", live);
+        assert!(live.iter().all(|meta| meta.level == 0), "Size-tiered compaction keeps segments in level 0");
+        for (key, value) in expected.iter() {
+            assert_eq!(db.get_str(key).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(db.scan(..).unwrap().count(), expected.len());
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_shared_across_threads() {
+        fn assert_shareable<T: Clone + Send + Sync>() {}
+        assert_shareable::<Database>();
+
+        let directory = PathBuf::from("/tmp/zdb_test_database_shared_across_threads");
+        let _ = fs::remove_dir_all(&directory);
+        let db = Database::new(directory.to_owned()).expect("Failed to create database");
+        db.set_sync_policy(SyncPolicy::Always).unwrap();
+        db.set_compaction_options(CompactionOptions { level0_segments: 2, ..CompactionOptions::default() }).unwrap();
+
+        // Writers fill enough memtables to flush and compact them, while readers keep scanning what is there
+        let writers = (0..4).map(|writer| {
+            let mut db = db.clone();
+            thread::spawn(move || {
+                for i in 0..1_000 {
+                    db.set_str(&format!("{}_{:04}", writer, i), &"value ".repeat(20)).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        let readers = (0..4).map(|_| {
+            let db = db.clone();
+            thread::spawn(move || {
+                let mut seen = 0;
+                for _ in 0..50 {
+                    // Each scan sees the database at a single point, so nothing written before it goes missing
+                    let keys = db.scan(..).unwrap().map(Result::unwrap).map(|(k, _)| k).collect::<Vec<_>>();
+                    assert!(keys.len() >= seen, "Scan lost keys: {} after {}", keys.len(), seen);
+                    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]), "Scan should return keys in order");
+                    seen = keys.len();
+
+                    let mut cursor = db.cursor().unwrap();
+                    if let Some((key, _)) = cursor.seek(b"").unwrap() {
+                        assert_eq!(db.get(&key).unwrap(), Some("value ".repeat(20).into_bytes()));
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+        writers.into_iter().chain(readers).for_each(|handle| handle.join().unwrap());
+
+        assert!(!db.read_state().unwrap().segments.is_empty(), "Memtables should have been flushed");
+        assert_eq!(db.scan(..).unwrap().count(), 4 * 1_000);
+        drop(db);
+
+        let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+        assert_eq!(db.scan(..).unwrap().count(), 4 * 1_000);
+        assert_eq!(db.get(b"3_0999").unwrap(), Some("value ".repeat(20).into_bytes()));
+
+        let _ = fs::remove_dir_all(&directory);
     }
-    
-}
\ No newline at end of file
+
+    #[test]
+    fn test_open_options() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_open_options");
+        let _ = fs::remove_dir_all(&directory);
+
+        let missing = Database::open(directory.to_owned(), DatabaseOptions::default().create_if_missing(false));
+        assert!(matches!(missing, Err(Error::NotFound(_))), "Missing database should not be created");
+        assert!(matches!(Database::open(directory.to_owned(), DatabaseOptions::default().read_only(true)), Err(Error::NotFound(_))));
+        assert!(!directory.exists());
+
+        // A tiny memtable is flushed after a handful of writes
+        let options = DatabaseOptions::default().memtable_bytes(1_000).block_bytes(100).compression(Compression::Lz).sync_policy(SyncPolicy::Always);
+        let mut db = Database::open(directory.to_owned(), options.clone().error_if_exists(true)).expect("Failed to create database");
+        for i in 0..20 {
+            db.set_str(&format!("key_{:02}", i), &"value ".repeat(20)).unwrap();
+        }
+        db.write_state().unwrap().finish_flush(true).unwrap();
+        assert!(db.read_state().unwrap().segments.len() >= 2, "Memtable should have been flushed more than once");
+        drop(db);
+
+        let existing = Database::open(directory.to_owned(), options.clone().error_if_exists(true));
+        assert!(matches!(existing, Err(Error::InvalidArgument(_))), "Existing database should be refused");
+
+        // Opened read-only, everything can be read but nothing in the directory changes
+        fs::write(directory.join("partial.tmp"), b"partial").unwrap();
+        let listing = || {
+            let mut files = fs::read_dir(&directory).unwrap()
+                .map(|entry| entry.unwrap())
+                .map(|entry| (entry.file_name(), entry.metadata().unwrap().len(), entry.metadata().unwrap().modified().unwrap()))
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        let before = listing();
+        let db = Database::open(directory.to_owned(), options.read_only(true)).expect("Failed to open database read-only");
+        for i in 0..20 {
+            assert_eq!(db.get_str(&format!("key_{:02}", i)).unwrap(), Some("value ".repeat(20)));
+        }
+        assert_eq!(db.scan(..).unwrap().count(), 20);
+        assert!(matches!(db.set(b"key_00", b"new"), Err(Error::NotSupported(_))));
+        assert!(matches!(db.delete(b"key_00"), Err(Error::NotSupported(_))));
+        drop(db);
+        assert_eq!(listing(), before);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_log_file() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_log_file");
+        let _ = fs::remove_dir_all(&directory);
+
+        // Names which could be taken for another file in the directory, or lie outside it, are refused
+        for log_file in ["", ".", "../write.log", "MANIFEST", "LOCK", "frozen.log", "old.seg", "partial.tmp"] {
+            let opened = Database::open(directory.to_owned(), DatabaseOptions::default().log_file(log_file));
+            assert!(matches!(opened, Err(Error::InvalidArgument(_))), "{:?} should be refused as a log name", log_file);
+        }
+        assert!(!directory.exists());
+
+        let options = DatabaseOptions::default().log_file("journal");
+        let db = Database::open(directory.to_owned(), options.clone()).expect("Failed to create database");
+        db.set(b"key", b"value").unwrap();
+        drop(db);
+        assert!(directory.join("journal").exists());
+        assert!(!directory.join("write.log").exists());
+
+        let db = Database::open(directory.to_owned(), options).expect("Failed to reopen database");
+        assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()), "Writes should be replayed from the named log");
+        drop(db);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_directory_lock() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_directory_lock");
+        let _ = fs::remove_dir_all(&directory);
+
+        let db = Database::new(directory.to_owned()).expect("Failed to create database");
+        db.set(b"key", b"value").unwrap();
+        assert!(matches!(Database::new(directory.to_owned()), Err(Error::Busy(_))), "A second writable open should be refused");
+
+        // Readers take no lock, so they can look at a database someone else is writing to
+        let reader = Database::open_read_only(directory.to_owned()).expect("Failed to open database read-only");
+        assert_eq!(reader.get(b"key").unwrap(), Some(b"value".to_vec()));
+
+        // Closing the database lets the next writer in
+        drop(db);
+        let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+        assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_open_read_only");
+        let _ = fs::remove_dir_all(&directory);
+
+        // Enough level 0 segments for a compaction, which the writer is kept from running
+        let options = DatabaseOptions::default().memtable_bytes(1_000).compaction(CompactionOptions { level0_segments: 1_000, ..CompactionOptions::default() });
+        let db = Database::open(directory.to_owned(), options).expect("Failed to create database");
+        let old = "old ".repeat(50);
+        for i in 0..20 {
+            db.set(format!("key_{:02}", i).as_bytes(), old.as_bytes()).unwrap();
+        }
+        db.write_state().unwrap().finish_flush(true).unwrap();
+        assert!(db.read_state().unwrap().segments.len() >= CompactionOptions::default().level0_segments);
+
+        // Writes made after a reader opens are not seen by it
+        let reader = Database::open_read_only(directory.to_owned()).expect("Failed to open database read-only");
+        db.set(b"key_00", b"new").unwrap();
+        assert_eq!(reader.get(b"key_00").unwrap(), Some(old.into_bytes()));
+        assert_eq!(reader.scan(..).unwrap().count(), 20);
+        drop(reader);
+        drop(db);
+
+        // A record torn by a writer still appending is skipped rather than cut off, and nothing is compacted
+        let mut log = OpenOptions::new().append(true).open(directory.join(DatabaseOptions::default().log_file)).unwrap();
+        log.write_all(&[3, 9, 0, 0]).unwrap();
+        drop(log);
+        let listing = || {
+            let mut files = fs::read_dir(&directory).unwrap()
+                .map(|entry| entry.unwrap())
+                .map(|entry| (entry.file_name(), entry.metadata().unwrap().len()))
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        let before = listing();
+
+        let mut reader = Database::open_read_only(directory.to_owned()).expect("Failed to open database read-only");
+        assert_eq!(reader.get(b"key_00").unwrap(), Some(b"new".to_vec()), "Log should be replayed into memory");
+        assert_eq!(reader.scan(..).unwrap().count(), 20);
+        assert!(matches!(reader.set_str("key_00", "newer"), Err(Error::NotSupported(_))));
+        let mut batch = WriteBatch::new();
+        batch.delete(b"key_01");
+        assert!(matches!(reader.write(batch), Err(Error::NotSupported(_))));
+        drop(reader);
+        assert_eq!(listing(), before, "Read-only open should leave the directory as it was");
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_read_only_alongside_writer() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_read_only_alongside_writer");
+        let _ = fs::remove_dir_all(&directory);
+
+        // A small memtable keeps the writer rotating its log, flushing and compacting while readers open
+        let db = Database::open(directory.to_owned(), DatabaseOptions::default().memtable_bytes(1_000)).expect("Failed to create database");
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 0..5_000 {
+                    db.set(format!("key_{:06}", i).as_bytes(), b"value").unwrap();
+                }
+            })
+        };
+
+        // Every reader sees the writes up to some point, with none missing before it
+        let mut opened = 0;
+        while !writer.is_finished() {
+            let reader = match Database::open_read_only(directory.to_owned()) {
+                Ok(reader) => reader,
+                Err(e) if changed_while_read(&e) => continue,
+                Err(e) => panic!("Failed to open database read-only: {:?}", e),
+            };
+            let keys = reader.scan(..).unwrap().map(|entry| entry.unwrap().0).collect::<Vec<_>>();
+            let expected = (0..keys.len()).map(|i| format!("key_{:06}", i).into_bytes()).collect::<Vec<_>>();
+            assert_eq!(keys, expected, "Reader should see a gap-free run of writes");
+            opened += 1;
+        }
+        writer.join().unwrap();
+        assert!(opened > 0, "Some read-only opens should succeed");
+
+        drop(db);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_snapshot");
+        let _ = fs::remove_dir_all(&directory);
+        let options = DatabaseOptions::default().memtable_bytes(1_000).compaction(CompactionOptions { level0_segments: 2, ..CompactionOptions::default() });
+        let db = Database::open(directory.to_owned(), options).expect("Failed to create database");
+
+        db.set(b"from", b"100").unwrap();
+        db.set(b"to", b"0").unwrap();
+        let snapshot = db.snapshot().unwrap();
+
+        // Neither half of a later transfer is seen by the snapshot
+        let mut batch = WriteBatch::new();
+        batch.set(b"from", b"50").set(b"to", b"50");
+        db.write(batch).unwrap();
+        db.delete(b"to").unwrap();
+        assert_eq!(db.snapshot().unwrap().sequence_number(), snapshot.sequence_number() + 2, "A batch takes a single sequence number");
+        assert_eq!(snapshot.get(b"from").unwrap(), Some(b"100".to_vec()));
+        assert_eq!(snapshot.get(b"to").unwrap(), Some(b"0".to_vec()));
+        assert_eq!(db.get(b"to").unwrap(), None);
+
+        // Flushing and compacting the overwritten keys keeps the segments the snapshot reads until it is dropped
+        let mut fillers = 0;
+        for round in 0..3 {
+            fillers = fillers.max(fill_memtable(&db, "filler", format!("round {}", round).as_bytes()));
+            db.write_state().unwrap().finish_flush(true).unwrap();
+        }
+        let older = db.snapshot().unwrap();
+        db.set(b"from", b"0").unwrap();
+        db.write_state().unwrap().finish_flush(true).unwrap();
+        db.write_state().unwrap().start_compaction().unwrap();
+        while db.write_state().unwrap().finish_compaction(true).unwrap() {
+            db.write_state().unwrap().start_compaction().unwrap();
+        }
+        let live = db.read_state().unwrap().manifest.live().to_vec();
+        assert!(live.iter().any(|meta| meta.level > 0), "Level 0 should have been compacted: {:?}", live);
+
+        assert_eq!(snapshot.get(b"from").unwrap(), Some(b"100".to_vec()));
+        assert_eq!(older.get(b"from").unwrap(), Some(b"50".to_vec()));
+        assert_eq!(older.get(b"filler_0000").unwrap(), Some(b"round 2".to_vec()));
+        let scanned = snapshot.scan(..).unwrap().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(scanned, vec![(b"from".to_vec(), b"100".to_vec()), (b"to".to_vec(), b"0".to_vec())]);
+        assert_eq!(older.scan_prefix(b"filler_").unwrap().count(), fillers);
+        let mut cursor = older.cursor();
+        assert_eq!(cursor.seek_for_prev(b"to").unwrap(), Some((b"from".to_vec(), b"50".to_vec())));
+
+        let segment_files = || {
+            let mut files = fs::read_dir(&directory).unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.ends_with(".seg"))
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        assert!(segment_files().len() > live.len(), "Replaced segments should be kept while snapshots read them");
+        drop((snapshot, older, cursor));
+        let mut live = live.iter().map(|meta| meta.file_name.to_owned()).collect::<Vec<_>>();
+        live.sort();
+        assert_eq!(segment_files(), live);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_snapshot_shares_memtable() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_snapshot_shares_memtable");
+        let _ = fs::remove_dir_all(&directory);
+        let db = Database::new(directory.to_owned()).expect("Failed to create database");
+
+        // Every write lands in the memtable the snapshots hold, rather than a copy of it, and each snapshot keeps seeing
+        // the value it was taken after
+        let memory = Arc::as_ptr(&db.read_state().unwrap().memory);
+        let mut snapshots = Vec::new();
+        for i in 0..1_000 {
+            db.set(b"key", i.to_string().as_bytes()).unwrap();
+            db.set(format!("key_{:04}", i).as_bytes(), b"value").unwrap();
+            snapshots.push(db.snapshot().unwrap());
+        }
+        assert_eq!(Arc::as_ptr(&db.read_state().unwrap().memory), memory, "Writes should not copy the memtable");
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            assert_eq!(snapshot.get(b"key").unwrap(), Some(i.to_string().into_bytes()));
+            assert_eq!(snapshot.scan_prefix(b"key_").unwrap().count(), i + 1);
+        }
+
+        // Once no snapshot holds the memtable, overwritten versions are dropped
+        drop(snapshots);
+        let usage = db.read_state().unwrap().memory.get_memory_usage();
+        db.set(b"key", b"last").unwrap();
+        assert!(db.read_state().unwrap().memory.get_memory_usage() < usage);
+        assert_eq!(db.get(b"key").unwrap(), Some(b"last".to_vec()));
+
+        drop(db);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_sequence_number_reopen() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_sequence_number");
+        let _ = fs::remove_dir_all(&directory);
+        let db = Database::open(directory.to_owned(), DatabaseOptions::default()).expect("Failed to create database");
+
+        for i in 0..3 {
+            db.set(format!("key_{}", i).as_bytes(), b"1").unwrap();
+        }
+        db.flush().unwrap();
+        assert_eq!(read_log(&directory.join("write.log")).unwrap().count(), 0, "Flush should have emptied the log");
+        let last = db.snapshot().unwrap().sequence_number();
+        drop(db);
+
+        // Flushed writes are only counted by the manifest, which is rewritten on every open
+        for _ in 0..2 {
+            let reader = Database::open(directory.to_owned(), DatabaseOptions::default().read_only(true)).unwrap();
+            assert_eq!(reader.snapshot().unwrap().sequence_number(), last);
+            drop(reader);
+            let db = Database::open(directory.to_owned(), DatabaseOptions::default()).unwrap();
+            assert_eq!(db.snapshot().unwrap().sequence_number(), last, "Sequence number should not go back after a reopen");
+        }
+
+        // Writes still in the log carry on from there
+        let db = Database::open(directory.to_owned(), DatabaseOptions::default()).unwrap();
+        db.delete(b"key_0").unwrap();
+        drop(db);
+        let db = Database::open(directory.to_owned(), DatabaseOptions::default()).unwrap();
+        assert_eq!(db.snapshot().unwrap().sequence_number(), last + 1);
+        assert_eq!(db.get(b"key_0").unwrap(), None);
+
+        drop(db);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_incomplete_flush() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_incomplete_flush");
+        let _ = fs::remove_dir_all(&directory);
+
+        let db = Database::new(directory.to_owned()).expect("Failed to create database");
+        let i = fill_memtable(&db, "key", "value ".repeat(20).as_bytes());
+        db.write_state().unwrap().finish_flush(true).unwrap();
+        drop(db);
+
+        // A flush which died before its segment was renamed, and one which died before the manifest named it
+        fs::write(directory.join("partial.tmp"), b"partial").unwrap();
+        fs::write(directory.join("unlisted.seg"), b"not a segment").unwrap();
+
+        let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+        assert!(!directory.join("partial.tmp").exists());
+        assert!(!directory.join("unlisted.seg").exists());
+        assert_eq!(db.scan(..).unwrap().count(), i);
+        let live = db.read_state().unwrap().manifest.live().to_vec();
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].file_name, db.read_state().unwrap().segments[0].get_file_name());
+        assert_eq!(live[0].smallest_key, b"key_0000");
+        assert_eq!(live[0].largest_key, format!("key_{:04}", i - 1).into_bytes());
+        drop(db);
+
+        // Databases written before the manifest adopt every segment they hold
+        fs::remove_file(directory.join("MANIFEST")).unwrap();
+        let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+        assert_eq!(db.read_state().unwrap().segments.len(), 1);
+        assert_eq!(db.scan(..).unwrap().count(), i);
+        assert!(directory.join("MANIFEST").exists());
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_legacy_segments() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_legacy_segments");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        // Segments as the first release wrote them: the sequence number, then each block's first key and its bare entries
+        let put = |output: &mut Vec<u8>, bytes: &[u8]| {
+            output.extend(bytes.len().to_ne_bytes());
+            output.extend(bytes);
+        };
+        let write_segment = |file_name: &str, sequence_number: usize, entries: &[(String, String)]| {
+            let mut bytes = sequence_number.to_ne_bytes().to_vec();
+            for block in entries.chunks(100) {
+                let mut data = Vec::new();
+                for (k, v) in block {
+                    put(&mut data, k.as_bytes());
+                    put(&mut data, v.as_bytes());
+                }
+                put(&mut bytes, block[0].0.as_bytes());
+                put(&mut bytes, &data);
+            }
+            fs::write(directory.join(file_name), bytes).unwrap();
+        };
+        let old = (0..300).map(|i| (format!("key_{:03}", i), "old".to_string())).collect::<Vec<_>>();
+        let new = (0..300).step_by(2).map(|i| (format!("key_{:03}", i), "new".to_string())).collect::<Vec<_>>();
+        write_segment("old.seg", 1, &old);
+        write_segment("new.seg", 2, &new);
+        write_segment("empty.seg", 3, &[]);
+
+        let options = DatabaseOptions::default().compaction(CompactionOptions { level0_segments: 3, ..CompactionOptions::default() });
+        let db = Database::open(directory.to_owned(), options.clone()).expect("Failed to open database of legacy segments");
+        let check = |db: &Database| {
+            assert_eq!(db.get_str("key_000").unwrap(), Some("new".to_string()));
+            assert_eq!(db.get_str("key_299").unwrap(), Some("old".to_string()));
+            assert_eq!(db.get_str("key_300").unwrap(), None);
+            assert_eq!(db.scan(..).unwrap().count(), 300);
+        };
+        check(&db);
+
+        // Compaction rewrites them in the current layout
+        db.write_state().unwrap().finish_compaction(true).unwrap();
+        assert!(!directory.join("old.seg").exists());
+        check(&db);
+        drop(db);
+        let db = Database::open(directory.to_owned(), options).expect("Failed to reopen database");
+        check(&db);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_torn_log() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_torn_log");
+        let _ = fs::remove_dir_all(&directory);
+        let mut db = Database::new(directory.to_owned()).expect("Failed to create database");
+
+        db.set_str("a", "1").unwrap();
+        db.set_str("b", "2").unwrap();
+        drop(db);
+
+        // The process died partway through appending a record
+        let mut log = OpenOptions::new().append(true).open(directory.join("write.log")).unwrap();
+        log.write_all(&[3, 9, 0, 0]).unwrap();
+        drop(log);
+
+        let mut db = Database::new(directory.to_owned()).expect("Failed to reopen database with a torn log");
+        assert_eq!(db.get_str("a").unwrap(), Some("1".to_string()));
+        assert_eq!(db.get_str("b").unwrap(), Some("2".to_string()));
+        assert_eq!(db.get_str("c").unwrap(), None);
+
+        // Records logged after recovery are not appended onto the torn one
+        db.set_str("c", "3").unwrap();
+        drop(db);
+        let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+        assert_eq!(db.get_str("c").unwrap(), Some("3".to_string()));
+        assert_eq!(db.get_str("a").unwrap(), Some("1".to_string()));
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_binary_keys_values() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_binary");
+        let _ = fs::remove_dir_all(&directory);
+        let db = Database::new(directory.to_owned()).expect("Failed to create database");
+
+        let key: &[u8] = b"\xff\x00\tkey\n";
+        let value: &[u8] = b"\x08\x96\x01\\\xc3\x28";
+        db.set(key, value).unwrap();
+        db.set(b"logged", value).unwrap();
+        assert_eq!(db.get(key).unwrap(), Some(value.to_vec()));
+        assert!(db.get_str("logged").is_err(), "Non UTF-8 value should not be returned as a string");
+
+        // Push the key out of memory and into a segment
+        fill_memtable(&db, "filler", b"value");
+        db.write_state().unwrap().finish_flush(true).unwrap();
+        assert!(!db.read_state().unwrap().segments.is_empty(), "Memory should have been flushed into a segment");
+        db.set(b"logged", key).unwrap();
+
+        drop(db);
+        let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+        assert_eq!(db.get(key).unwrap(), Some(value.to_vec()), "Binary pair should be read back from the segment");
+        assert_eq!(db.get(b"logged").unwrap(), Some(key.to_vec()), "Binary value should be replayed from the log");
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_mixed_compression() {
+        let directory = PathBuf::from("/tmp/zdb_test_database_compression");
+        let _ = fs::remove_dir_all(&directory);
+
+        // Write one segment uncompressed and another compressed
+        let value = "compressible ".repeat(50);
+        let mut count = 0;
+        for (compression, prefix) in [(Compression::None, "plain"), (Compression::Lz, "packed")] {
+            let db = Database::open(directory.to_owned(), DatabaseOptions::default().compression(compression)).expect("Failed to open database");
+            count += fill_memtable(&db, prefix, value.as_bytes());
+            db.write_state().unwrap().finish_flush(true).unwrap();
+        }
+
+        let sizes = fs::read_dir(&directory).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "seg"))
+            .map(|path| fs::metadata(path).unwrap().len())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes.len(), 2);
+        assert!(sizes.iter().min().unwrap() * 10 < *sizes.iter().max().unwrap(), "Compressed segment should be much smaller: {:?}", sizes);
+
+        // Compacting reads both codecs back
+        let db = Database::new(directory.to_owned()).expect("Failed to reopen database");
+        db.set_compaction_options(CompactionOptions { level0_segments: 2, ..CompactionOptions::default() }).unwrap();
+        db.write_state().unwrap().start_compaction().unwrap();
+        db.write_state().unwrap().finish_compaction(true).unwrap();
+        assert_eq!(db.read_state().unwrap().segments.len(), 1);
+        assert_eq!(db.get_str("plain_0000").unwrap(), Some(value.to_owned()));
+        assert_eq!(db.get_str("packed_0000").unwrap(), Some(value.to_owned()));
+        assert_eq!(db.scan(..).unwrap().count(), count);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+}
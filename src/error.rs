@@ -0,0 +1,63 @@
+use std::{fmt, io};
+
+// Every way an operation on the database can fail. More may be added, so matches outside the crate need a catch-all arm.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    Io(io::Error),
+    // Data read back from disk does not match what was written. Retrying does not help.
+    Corruption(String),
+    // The caller asked for something which can never succeed, such as a reversed range or an oversized key.
+    InvalidArgument(String),
+    // A file the database relies on is missing.
+    NotFound(String),
+    // A file was written in a format this version does not understand.
+    NotSupported(String),
+    // Someone else holds what the operation needs.
+    Busy(String),
+    // A background flush or compaction died without reporting why.
+    Internal(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "IO error: {}", e),
+            Error::Corruption(message) => write!(f, "Corruption: {}", message),
+            Error::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            Error::NotFound(message) => write!(f, "Not found: {}", message),
+            Error::NotSupported(message) => write!(f, "Not supported: {}", message),
+            Error::Busy(message) => write!(f, "Busy: {}", message),
+            Error::Internal(message) => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_error() {
+        let error = Error::from(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+        assert!(matches!(&error, Error::Io(e) if e.kind() == io::ErrorKind::PermissionDenied));
+        assert!(error.source().is_some(), "IO errors should expose their cause");
+        assert_eq!(Error::Corruption("bad block".to_string()).to_string(), "Corruption: bad block");
+    }
+}
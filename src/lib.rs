@@ -1,13 +1,50 @@
 pub mod database;
+pub mod cursor;
+pub mod snapshot;
+pub mod write_batch;
+pub mod compression;
+pub mod options;
+mod checksum;
+pub mod error;
 mod memory_store;
 mod log_store;
 mod segment_store;
+mod merge_iterator;
+mod bloom_filter;
+mod manifest;
+mod compaction;
 
-use std::error::Error;
+pub use error::Error;
 
-type SetResult = Result<(), Box<dyn Error>>;
-type GetResult = Result<Option<String>, Box<dyn Error>>;
+type SetResult = Result<(), Error>;
+type GetResult = Result<Option<Vec<u8>>, Error>;
+type DeleteResult = Result<(), Error>;
+type GetStrResult = Result<Option<String>, Error>;
+// A key with its value, where a `None` value marks a deleted key.
+type Entry = (Vec<u8>, Option<Vec<u8>>);
 pub trait Storage {
-    fn set(&mut self, key: &str, value: &str) -> SetResult;
-    fn get(&self, key: &str) -> GetResult;
-}
\ No newline at end of file
+    fn set(&mut self, key: &[u8], value: &[u8]) -> SetResult;
+    fn get(&self, key: &[u8]) -> GetResult;
+    fn delete(&mut self, key: &[u8]) -> DeleteResult;
+}
+
+// Convenience layer for storing UTF-8 keys and values.
+pub trait StrStorage: Storage {
+    fn set_str(&mut self, key: &str, value: &str) -> SetResult {
+        self.set(key.as_bytes(), value.as_bytes())
+    }
+
+    // Fails if the stored value is not valid UTF-8.
+    fn get_str(&self, key: &str) -> GetStrResult {
+        match self.get(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value).map_err(|_| Error::InvalidArgument(format!("Value of {} is not valid UTF-8", key)))?)),
+            None => Ok(None),
+        }
+    }
+
+    fn delete_str(&mut self, key: &str) -> DeleteResult {
+        self.delete(key.as_bytes())
+    }
+}
+
+impl<T: Storage + ?Sized> StrStorage for T {}
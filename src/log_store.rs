@@ -1,144 +1,740 @@
-use std::{fs::{File, OpenOptions}, io::{self, BufRead, BufReader, Write}, path::PathBuf};
+use std::{collections::VecDeque, fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Read, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc, Condvar, Mutex, MutexGuard}, thread::{self, JoinHandle}, time::{Duration, Instant}};
 
-use crate::{GetResult, SetResult, Storage};
+use log::{info, warn};
+
+use crate::{checksum::crc32, options::SyncPolicy, segment_store::sync_directory, write_batch::WriteBatch, DeleteResult, Entry, Error, GetResult, SetResult, Storage};
+
+// Leads every binary log, followed by the format version. Logs without it are in the older text format.
+const MAGIC: &[u8; 8] = b"zdb\0wal\0";
+const FORMAT_VERSION: u32 = 2;
+// Before record headers had a checksum of their own, so a damaged length could not be told from a torn append.
+const UNCHECKED_VERSION: u32 = 1;
+const FILE_HEADER_SIZE: usize = MAGIC.len() + 4;
+
+// Each record is its type, sequence number, payload length and a checksum over those, then the payload and a checksum
+// over everything before it.
+const RECORD_HEADER_SIZE: usize = 1 + 8 + 4 + 4;
+const UNCHECKED_HEADER_SIZE: usize = 1 + 8 + 4;
+const PUT: u8 = 1;
+const DELETE: u8 = 2;
+// Payload holds every operation of a write batch, each led by its own `PUT` or `DELETE` type.
+const BATCH: u8 = 3;
+
+// Leads a text line holding a whole write batch.
+const TEXT_BATCH_MARKER: &[u8] = b"\\B";
+// Leads the checksum of a text record, written as hex before the record's first tab.
+const TEXT_CHECKSUM_MARKER: &[u8] = b"\\C";
 
  pub struct LogStore {
     file_path: PathBuf,
     writer: File,
+    sync_policy: SyncPolicy,
+    group_commit: Arc<GroupCommit>,
+    syncer: Option<JoinHandle<()>>, // Syncs on a timer under `SyncPolicy::Interval`
+}
+
+// Lets writers which logged at about the same time share a single fsync. Positions are counted in bytes appended
+// over the life of the log, so they keep growing when the file is truncated.
+pub struct GroupCommit {
+    file: File,
+    appended: AtomicU64,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+// A record which has been appended, and which the sync policy may still require to be forced to disk.
+#[must_use]
+pub struct PendingSync {
+    group_commit: Option<Arc<GroupCommit>>,
+    position: u64,
+}
+
+impl PendingSync {
+    // Blocks until the record is on disk, if the sync policy asks for it.
+    pub fn wait(self) -> SetResult {
+        if let Some(group_commit) = self.group_commit {
+            group_commit.sync_to(self.position)?;
+        }
+        Ok(())
+    }
+}
+
+struct SyncState {
+    synced_to: u64,
+    syncing: bool, // Whether a writer is already syncing on behalf of the others
+    last_sync: Instant,
+    syncs: usize,
+    closed: bool, // Tells the background syncer to stop
+}
+
+// Outcome of reading the next record from the log.
+enum Record {
+    Complete { len: usize, sequence_number: u64, operations: VecDeque<Entry> },
+    // The record does not match its checksum.
+    Corrupt { len: usize },
+    // The header does not match its checksum, so where the record ends is unknown.
+    CorruptHeader,
+    // The log ends partway through the record.
+    Torn,
+    End,
 }
 
 impl LogStore {
 
-    pub fn init(file_path: PathBuf) -> LogStore {
-        LogStore {
-            file_path: file_path.to_owned(),
-            writer: OpenOptions::new()
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(file_path)
-                .unwrap(),
+    // Opens the log, first rewriting a log left in the text format or an older binary one into the current format.
+    pub fn init(file_path: PathBuf) -> Result<LogStore, Error> {
+        let is_empty = fs::metadata(&file_path).map(|m| m.len() == 0).unwrap_or(true);
+        if is_empty {
+            write_file_header(&mut File::create(&file_path)?)?;
+        } else {
+            match read_file_header(&file_path)? {
+                None => migrate_text_log(&file_path)?,
+                Some(UNCHECKED_VERSION) => migrate_unchecked_log(&file_path)?,
+                Some(_) => {}
+            }
         }
+
+        let writer = OpenOptions::new()
+            .append(true)
+            .open(&file_path)?;
+        Ok(LogStore::from_writer(file_path, writer)?)
     }
 
-    pub fn iter(&self) -> io::Result<LogStoreIterator> {
-        let file = File::open(&self.file_path)?;
-        Ok(LogStoreIterator {
-            lines:  io::BufReader::new(file).lines()
+    fn from_writer(file_path: PathBuf, writer: File) -> io::Result<LogStore> {
+        Ok(LogStore {
+            file_path,
+            group_commit: Arc::new(GroupCommit {
+                file: writer.try_clone()?,
+                appended: AtomicU64::new(0),
+                state: Mutex::new(SyncState {
+                    synced_to: 0,
+                    syncing: false,
+                    last_sync: Instant::now(),
+                    syncs: 0,
+                    closed: false,
+                }),
+                synced: Condvar::new(),
+            }),
+            writer,
+            sync_policy: SyncPolicy::default(),
+            syncer: None,
         })
     }
 
-    pub fn flush(&mut self) -> io::Result<()> {
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) {
+        self.stop_syncer();
+        self.sync_policy = sync_policy;
+        if let SyncPolicy::Interval(interval) = sync_policy {
+            let group_commit = self.group_commit.to_owned();
+            self.syncer = Some(thread::spawn(move || {
+                if let Err(e) = group_commit.sync_periodically(interval) {
+                    warn!("Background log syncer stopped: {}", e);
+                }
+            }));
+        }
+    }
+
+    // A poisoned lock has already stopped the syncer, so there is nothing to tell it.
+    fn stop_syncer(&mut self) {
+        if let Some(syncer) = self.syncer.take() {
+            if let Ok(mut state) = self.group_commit.lock_state() {
+                state.closed = true;
+            }
+            self.group_commit.synced.notify_all();
+            if syncer.join().is_err() {
+                warn!("Background log syncer panicked");
+            }
+            if let Ok(mut state) = self.group_commit.lock_state() {
+                state.closed = false;
+            }
+        }
+    }
+
+    pub fn iter(&self) -> io::Result<LogStoreIterator> {
+        iter_from_header(&self.file_path, FORMAT_VERSION)
+    }
+
+    // Cuts off a record left half written by a crash during its append, so later records are not appended onto it.
+    // Only the final record can be torn, so damage followed by any intact record is reported rather than cut off with
+    // the committed records after it.
+    pub fn recover(&mut self) -> Result<(), Error> {
+        let file_len = fs::metadata(&self.file_path)?.len() as usize;
+        let mut reader = BufReader::new(File::open(&self.file_path)?);
+        reader.read_exact(&mut [0u8; FILE_HEADER_SIZE])?;
+
+        let mut valid_len = FILE_HEADER_SIZE;
+        loop {
+            match read_record(&mut reader, FORMAT_VERSION)? {
+                Record::Complete { len, .. } => valid_len += len,
+                Record::Corrupt { len } if valid_len + len < file_len => {
+                    return Err(Error::Corruption(format!("Record in the middle of {} does not match its checksum", self.file_path.display())));
+                }
+                Record::CorruptHeader if !is_torn(&fs::read(&self.file_path)?[valid_len + RECORD_HEADER_SIZE..]) => {
+                    return Err(Error::Corruption(format!("Record header in the middle of {} does not match its checksum", self.file_path.display())));
+                }
+                Record::Corrupt { .. } | Record::CorruptHeader | Record::Torn | Record::End => break,
+            }
+        }
+
+        if valid_len < file_len {
+            warn!("Truncating torn record of {} bytes from the end of {}", file_len - valid_len, self.file_path.display());
+            self.writer.set_len(valid_len as u64)?;
+            self.writer.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    // Logs every operation of the batch as a single record, which is only replayed once it is complete. Records carry
+    // the sequence number the caller gave the write, since the log keeps no count of its own.
+    pub fn write_batch(&mut self, sequence_number: u64, batch: &WriteBatch) -> SetResult {
+        self.log_batch(sequence_number, batch)?.wait()
+    }
+
+    // Appends the batch without waiting for it to reach disk, so the caller can release its locks first.
+    pub fn log_batch(&mut self, sequence_number: u64, batch: &WriteBatch) -> Result<PendingSync, Error> {
+        let mut payload = Vec::new();
+        for (key, value) in batch.iter() {
+            payload.push(if value.is_some() { PUT } else { DELETE });
+            encode_operation(&mut payload, key, value.as_deref())?;
+        }
+
+        self.append_record(BATCH, sequence_number, &payload)
+    }
+
+    // Appends a single set, or a deletion when there is no value, without waiting for it to reach disk.
+    pub fn log_operation(&mut self, sequence_number: u64, key: &[u8], value: Option<&[u8]>) -> Result<PendingSync, Error> {
+        let mut payload = Vec::new();
+        encode_operation(&mut payload, key, value)?;
+
+        self.append_record(if value.is_some() { PUT } else { DELETE }, sequence_number, &payload)
+    }
+
+    fn append_record(&mut self, record_type: u8, sequence_number: u64, payload: &[u8]) -> Result<PendingSync, Error> {
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len() + 4);
+        record.push(record_type);
+        record.extend(sequence_number.to_ne_bytes());
+        record.extend(u32::try_from(payload.len()).map_err(|_| Error::InvalidArgument("Write is too large to log".to_string()))?.to_ne_bytes());
+        record.extend(crc32(&record).to_ne_bytes());
+        record.extend_from_slice(payload);
+        record.extend(crc32(&record).to_ne_bytes());
+
+        self.append(&record)
+    }
+
+    fn append(&mut self, entry: &[u8]) -> Result<PendingSync, Error> {
+        self.writer.write_all(entry)?;
+
+        let size = entry.len() as u64;
+        let appended = self.group_commit.appended.fetch_add(size, Ordering::SeqCst) + size;
+        let group_commit = match self.group_commit.sync_due(self.sync_policy, appended)? {
+            true => Some(self.group_commit.to_owned()),
+            false => None,
+        };
+        Ok(PendingSync { group_commit, position: appended })
+    }
+
+    // Moves the log aside to the path and carries on in a new empty log, so writes can continue while the memtable
+    // built from the old one is written out.
+    pub fn rotate(&mut self, frozen_path: &Path) -> Result<(), Error> {
         self.writer.sync_all()?;
-        self.writer = std::fs::OpenOptions::new().truncate(true).write(true).open(self.file_path.to_owned())?;
+        fs::rename(&self.file_path, frozen_path)?;
+
+        let mut log = LogStore::init(self.file_path.to_owned())?;
+        sync_directory(&self.file_path)?;
+        log.set_sync_policy(self.sync_policy);
+        *self = log;
 
         Ok(())
     }
 
 }
 
+impl Drop for LogStore {
+    fn drop(&mut self) {
+        self.stop_syncer();
+    }
+}
+
+// Writes made straight to the log are not numbered, as only the database hands out sequence numbers.
 impl Storage for LogStore {
 
-    fn set(&mut self, key: &str, value: &str) -> SetResult {
-
-        
-        let mut entry = String::new();
-        entry.push_str(&serialize(key));
-        entry.push_str("\t");
-        entry.push_str(&serialize(value));
-        entry.push_str("\n");
-
-        match self.writer.write(entry.as_bytes()) {
-            Ok(size) => {
-                if size == entry.len() {
-                    Ok(())
-                } else {
-                    Err("Failed to write all bytes into log".into())
+    fn set(&mut self, key: &[u8], value: &[u8]) -> SetResult {
+        self.log_operation(0, key, Some(value))?.wait()
+    }
+
+    fn get(&self, key: &[u8]) -> GetResult {
+        let entries = self.iter()?;
+
+        let mut latest: Option<Vec<u8>> = None;
+        for entry in entries {
+            let (k, v) = entry?;
+            if k == key {
+                latest = v;
+            }
+        }
+
+        Ok(latest)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> DeleteResult {
+        self.log_operation(0, key, None)?.wait()
+    }
+}
+
+impl GroupCommit {
+    // A writer which panicked partway through a sync may have left the state half changed, so nobody trusts it again.
+    fn lock_state(&self) -> Result<MutexGuard<'_, SyncState>, Error> {
+        self.state.lock().map_err(|_| poisoned())
+    }
+
+    fn sync_due(&self, sync_policy: SyncPolicy, appended: u64) -> Result<bool, Error> {
+        let state = self.lock_state()?;
+        Ok(match sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => state.last_sync.elapsed() >= interval,
+            SyncPolicy::Bytes(bytes) => appended - state.synced_to >= bytes as u64,
+            SyncPolicy::Never => false,
+        })
+    }
+
+    // Blocks until everything appended up to the position is on disk. Whoever finds no sync running starts one covering
+    // every write appended so far, and the writers waiting behind it return once it finishes.
+    pub fn sync_to(&self, position: u64) -> SetResult {
+        let mut state = self.lock_state()?;
+        while state.syncing && state.synced_to < position {
+            state = self.synced.wait(state).map_err(|_| poisoned())?;
+        }
+        if state.synced_to >= position {
+            return Ok(());
+        }
+
+        state.syncing = true;
+        let target = self.appended.load(Ordering::SeqCst);
+        drop(state);
+
+        let result = self.file.sync_data();
+
+        let mut state = self.lock_state()?;
+        state.syncing = false;
+        if result.is_ok() {
+            state.synced_to = state.synced_to.max(target);
+            state.last_sync = Instant::now();
+            state.syncs += 1;
+        }
+        self.synced.notify_all();
+        Ok(result?)
+    }
+
+    // Syncs writes left waiting once an interval has passed since the last sync, so the last writes before the log goes
+    // quiet are not left unsynced. Runs until the log is closed.
+    fn sync_periodically(&self, interval: Duration) -> SetResult {
+        let mut state = self.lock_state()?;
+        while !state.closed {
+            let appended = self.appended.load(Ordering::SeqCst);
+            let since_sync = state.last_sync.elapsed();
+            let timeout = if appended > state.synced_to && since_sync >= interval {
+                drop(state);
+                if let Err(e) = self.sync_to(appended) {
+                    warn!("Failed to sync the log in the background: {}", e);
                 }
+                state = self.lock_state()?;
+                interval
+            } else if since_sync >= interval {
+                interval
+            } else {
+                interval - since_sync
+            };
+            // Syncs by writers and closing the log wake it early
+            state = self.synced.wait_timeout(state, timeout).map_err(|_| poisoned())?.0;
+        }
+        Ok(())
+    }
+}
+
+fn poisoned() -> Error {
+    Error::Internal("Log sync state was poisoned by a panicked writer".to_string())
+}
+
+// Reads the log without opening it for writing, so nothing is truncated or migrated. A log still in the text format
+// has to be opened for writing once first, while older binary logs are read as they are.
+pub fn read_log(file_path: &Path) -> Result<LogStoreIterator, Error> {
+    // Created but never written to, which a writable open would give a header
+    if fs::metadata(file_path)?.len() == 0 {
+        return Ok(LogStoreIterator { reader: BufReader::new(File::open(file_path)?), version: FORMAT_VERSION, pending: VecDeque::new(), sequence_number: 0 });
+    }
+    match read_file_header(file_path)? {
+        Some(version) => Ok(iter_from_header(file_path, version)?),
+        None => Err(Error::NotSupported(format!("Log {} is in the text format and must be migrated by a writable open", file_path.display()))),
+    }
+}
+
+fn iter_from_header(file_path: &Path, version: u32) -> io::Result<LogStoreIterator> {
+    let mut reader = BufReader::new(File::open(file_path)?);
+    reader.read_exact(&mut [0u8; FILE_HEADER_SIZE])?;
+    Ok(LogStoreIterator {
+        reader,
+        version,
+        pending: VecDeque::new(),
+        sequence_number: 0,
+    })
+}
+
+fn write_file_header(writer: &mut File) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_ne_bytes())
+}
+
+// Returns the format version, or `None` if the log is in the text format.
+fn read_file_header(file_path: &Path) -> Result<Option<u32>, Error> {
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    let read = read_fully(&mut File::open(file_path)?, &mut header)?;
+    if read < FILE_HEADER_SIZE || !header.starts_with(MAGIC) {
+        return Ok(None);
+    }
+
+    let version = u32::from_ne_bytes(header[MAGIC.len()..].try_into().unwrap());
+    if version != FORMAT_VERSION && version != UNCHECKED_VERSION {
+        return Err(Error::NotSupported(format!("Log {} has unsupported format version {}", file_path.display(), version)));
+    }
+    Ok(Some(version))
+}
+
+// Key and value lengths followed by their bytes, with no value for a deletion.
+fn encode_operation(payload: &mut Vec<u8>, key: &[u8], value: Option<&[u8]>) -> SetResult {
+    put_bytes(payload, key)?;
+    if let Some(value) = value {
+        put_bytes(payload, value)?;
+    }
+    Ok(())
+}
+
+// Writes the bytes led by their length.
+pub fn put_bytes(output: &mut Vec<u8>, bytes: &[u8]) -> SetResult {
+    let len = u32::try_from(bytes.len()).map_err(|_| Error::InvalidArgument(format!("{} bytes is too long to store", bytes.len())))?;
+    output.extend(len.to_ne_bytes());
+    output.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn read_record(reader: &mut impl Read, version: u32) -> io::Result<Record> {
+    let header_size = if version == UNCHECKED_VERSION { UNCHECKED_HEADER_SIZE } else { RECORD_HEADER_SIZE };
+    let mut header = [0u8; RECORD_HEADER_SIZE];
+    let header = &mut header[..header_size];
+    match read_fully(reader, header)? {
+        0 => return Ok(Record::End),
+        read if read == header_size => {}
+        _ => return Ok(Record::Torn),
+    }
+    // The length is only trusted once the header matches, so a damaged one is not taken for a record cut off by the
+    // end of the log
+    if version != UNCHECKED_VERSION && u32::from_ne_bytes(header[13..].try_into().unwrap()) != crc32(&header[..13]) {
+        return Ok(Record::CorruptHeader);
+    }
+
+    let record_type = header[0];
+    let sequence_number = u64::from_ne_bytes(header[1..9].try_into().unwrap());
+    let payload_len = u32::from_ne_bytes(header[9..13].try_into().unwrap()) as usize;
+
+    // Read without allocating the length up front, so a corrupt length cannot exhaust memory
+    let mut rest = Vec::new();
+    reader.take(payload_len as u64 + 4).read_to_end(&mut rest)?;
+    if rest.len() < payload_len + 4 {
+        return Ok(Record::Torn);
+    }
+
+    let len = header_size + rest.len();
+    let (payload, checksum) = rest.split_at(payload_len);
+    let mut checked = header.to_vec();
+    checked.extend_from_slice(payload);
+    if u32::from_ne_bytes(checksum.try_into().unwrap()) != crc32(&checked) {
+        return Ok(Record::Corrupt { len });
+    }
+
+    match parse_payload(record_type, payload) {
+        Some(operations) => Ok(Record::Complete { len, sequence_number, operations }),
+        None => Ok(Record::Corrupt { len }),
+    }
+}
+
+// Whether no intact record starts anywhere in the bytes after a damaged header. Only then can the damage be an append
+// cut short by a crash, rather than a record hiding the committed ones after it.
+fn is_torn(rest: &[u8]) -> bool {
+    (0..rest.len()).all(|start| !matches!(read_record(&mut &rest[start..], FORMAT_VERSION), Ok(Record::Complete { .. })))
+}
+
+fn parse_payload(record_type: u8, mut payload: &[u8]) -> Option<VecDeque<Entry>> {
+    let mut operations = VecDeque::new();
+    match record_type {
+        PUT | DELETE => operations.push_back(parse_operation(record_type, &mut payload)?),
+        BATCH => {
+            while let Some((&op, rest)) = payload.split_first() {
+                payload = rest;
+                operations.push_back(parse_operation(op, &mut payload)?);
             }
-            Err(e) => Err(e.into())
         }
+        _ => return None,
     }
 
-    fn get(&self, key: &str) -> GetResult {
-        let entries = match self.iter() {
-            Ok(entries) => entries,
-            Err(e) => return Err(e.into())
+    if payload.is_empty() {
+        Some(operations)
+    } else {
+        None
+    }
+}
+
+fn parse_operation(op: u8, payload: &mut &[u8]) -> Option<Entry> {
+    let key = take_bytes(payload)?;
+    match op {
+        PUT => Some((key, Some(take_bytes(payload)?))),
+        DELETE => Some((key, None)),
+        _ => None,
+    }
+}
+
+// Reads bytes written by `put_bytes` off the front of the input.
+pub fn take_bytes(input: &mut &[u8]) -> Option<Vec<u8>> {
+    if input.len() < 4 {
+        return None;
+    }
+    let (len, rest) = input.split_at(4);
+    let len = u32::from_ne_bytes(len.try_into().unwrap()) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (bytes, rest) = rest.split_at(len);
+    *input = rest;
+    Some(bytes.to_vec())
+}
+
+// Reads until the buffer is full or the reader runs out, returning how much was read.
+fn read_fully(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+// Rewrites a text format log into a binary one.
+fn migrate_text_log(file_path: &Path) -> SetResult {
+    let text = fs::read(file_path)?;
+    let mut batches = Vec::new();
+    let mut lines = text.split_inclusive(|&b| b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        // A record is only complete once its trailing newline made it into the log
+        let line = match line.strip_suffix(b"\n") {
+            Some(line) => line,
+            None => {
+                warn!("Ignoring incomplete record at the end of the log");
+                break;
+            }
         };
 
-        let mut latest: Option<String> = None;
-        for (k, v) in entries {
-            if k == key {
-                latest = Some(v);
+        let record = match verify_text_record(line) {
+            Ok(record) => record,
+            Err(_) if lines.peek().is_none() => {
+                warn!("Ignoring torn record at the end of the log");
+                break;
             }
+            Err(e) => return Err(e),
+        };
+
+        let operations = match parse_text_record(record) {
+            Some(operations) => operations,
+            None => {
+                warn!("Ignoring malformed record in the log");
+                continue;
+            }
+        };
+
+        batches.push((batches.len() as u64 + 1, to_batch(operations)));
+    }
+
+    write_migrated(file_path, batches)
+}
+
+// Rewrites a log whose record headers have no checksum, keeping the sequence number of every record.
+fn migrate_unchecked_log(file_path: &Path) -> SetResult {
+    let mut reader = BufReader::new(File::open(file_path)?);
+    reader.read_exact(&mut [0u8; FILE_HEADER_SIZE])?;
+
+    let mut batches = Vec::new();
+    loop {
+        match read_record(&mut reader, UNCHECKED_VERSION)? {
+            Record::Complete { sequence_number, operations, .. } => batches.push((sequence_number, to_batch(operations))),
+            Record::Corrupt { .. } if !reader.fill_buf()?.is_empty() => {
+                return Err(Error::Corruption(format!("Record in the middle of {} does not match its checksum", file_path.display())));
+            }
+            Record::Corrupt { .. } | Record::Torn => {
+                warn!("Ignoring torn record at the end of the log");
+                break;
+            }
+            Record::CorruptHeader | Record::End => break,
         }
+    }
 
-        Ok(latest)
+    write_migrated(file_path, batches)
+}
+
+fn to_batch(operations: impl IntoIterator<Item = Entry>) -> WriteBatch {
+    let mut batch = WriteBatch::new();
+    for (key, value) in operations {
+        match value {
+            Some(value) => batch.set(&key, &value),
+            None => batch.delete(&key),
+        };
+    }
+    batch
+}
+
+// Writes the migrated records to a log next to the old one, then swaps it into place so a crash leaves one or the other.
+fn write_migrated(file_path: &Path, batches: Vec<(u64, WriteBatch)>) -> SetResult {
+    let migrated_path = file_path.with_extension("migrate");
+    let mut migrated = LogStore::from_writer(migrated_path.to_owned(), File::create(&migrated_path)?)?;
+    write_file_header(&mut migrated.writer)?;
+    for (sequence_number, batch) in batches.iter() {
+        migrated.write_batch(*sequence_number, batch)?;
     }
+
+    migrated.writer.sync_all()?;
+    fs::rename(&migrated_path, file_path)?;
+    info!("Migrated {} records of {} to the current log format", batches.len(), file_path.display());
+    Ok(())
 }
 
-fn serialize(input: &str) -> String {
-    input.replace("\\","\\\\")
-         .replace("\n", "\\n")
-         .replace("\t", "\\t")
+// Strips the checksum field from a text line, failing if the record does not match it. Lines logged before
+// checksums were added have no such field and are replayed unchecked.
+fn verify_text_record(line: &[u8]) -> Result<&[u8], Error> {
+    let field = match line.strip_prefix(TEXT_CHECKSUM_MARKER) {
+        Some(field) => field,
+        None => return Ok(line),
+    };
+
+    let (checksum, record) = match field.iter().position(|&b| b == b'\t') {
+        Some(i) => (&field[..i], &field[i + 1..]),
+        None => return Err(Error::Corruption("Log record is missing its checksum".to_string())),
+    };
+    let checksum = std::str::from_utf8(checksum).ok().and_then(|c| u32::from_str_radix(c, 16).ok());
+    if checksum != Some(crc32(record)) {
+        return Err(Error::Corruption("Log record does not match its checksum".to_string()));
+    }
+
+    Ok(record)
 }
 
+fn parse_text_record(line: &[u8]) -> Option<VecDeque<Entry>> {
+    let mut fields = line.split(|&b| b == b'\t');
+    let mut operations = VecDeque::new();
 
-fn deserialize(input: &str) -> String {
-    let mut result = String::new();
+    match fields.next()? {
+        TEXT_BATCH_MARKER => {
+            while let Some(op) = fields.next() {
+                let key = deserialize(fields.next()?);
+                match op {
+                    b"+" => operations.push_back((key, Some(deserialize(fields.next()?)))),
+                    b"-" => operations.push_back((key, None)),
+                    _ => return None,
+                }
+            }
+        }
+        key => operations.push_back((deserialize(key), fields.next().map(deserialize))),
+    }
 
-    let mut chars = input.chars().peekable();
+    Some(operations)
+}
+
+// Undoes the escaping of the bytes which delimited fields and records in the text format.
+fn deserialize(input: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
 
-    while let Some(&ch) = chars.peek() {        
-        match ch {
-            '\\' => {
-                chars.next();
-                match chars.peek() {
-                    Some('n') => {
-                        result.push('\n');
+    let mut bytes = input.iter().peekable();
+
+    while let Some(&&byte) = bytes.peek() {
+        match byte {
+            b'\\' => {
+                bytes.next();
+                match bytes.peek() {
+                    Some(b'n') => {
+                        result.push(b'\n');
                     }
-                    Some('t') => {
-                        result.push('\t');
+                    Some(b't') => {
+                        result.push(b'\t');
                     }
-                    Some('\\') => {
-                        result.push('\\');
+                    Some(b'\\') => {
+                        result.push(b'\\');
                     }
                     _ => {
-                        result.push('\\');
-                        result.push(ch);
+                        result.push(b'\\');
+                        result.push(byte);
                     }
                 }
             }
             _ => {
-                result.push(ch);
+                result.push(byte);
             }
         }
 
-        chars.next();
+        bytes.next();
     }
 
     result
 }
 
 pub struct LogStoreIterator {
-    lines: std::io::Lines<BufReader<File>>
+    reader: BufReader<File>,
+    version: u32,
+    pending: VecDeque<Entry>, // Remaining operations of the last batch read
+    sequence_number: u64, // Of the last record read
+}
+
+impl LogStoreIterator {
+    pub fn sequence_number(&self) -> u64 {
+        self.sequence_number
+    }
 }
 
 impl Iterator for LogStoreIterator {
-    type Item = (String, String);
-    
+    // A `None` value marks a deleted key.
+    type Item = Result<Entry, Error>;
+
     fn next(&mut self) -> Option<Self::Item> {
-        let line = self.lines.next();
-
-        match line {
-            Some(Ok(line)) => {
-                let mut parts = line.split("\t");
-                let key = parts.next().unwrap();
-                let value = parts.next().unwrap();
-                Some((deserialize(key), deserialize(value)))
-            }
-            _ => None
+        while self.pending.is_empty() {
+            self.pending = match read_record(&mut self.reader, self.version) {
+                Ok(Record::Complete { sequence_number, operations, .. }) => {
+                    self.sequence_number = sequence_number;
+                    operations
+                }
+                Ok(Record::End) => return None,
+                Ok(Record::Torn) => {
+                    warn!("Ignoring incomplete record at the end of the log");
+                    return None;
+                }
+                // Only a reader which cannot truncate the log finds this, as `recover` cuts it off the same way
+                Ok(Record::Corrupt { .. }) if self.reader.fill_buf().is_ok_and(|rest| rest.is_empty()) => {
+                    warn!("Ignoring incomplete record at the end of the log");
+                    return None;
+                }
+                Ok(Record::Corrupt { .. }) => return Some(Err(Error::Corruption("Log record does not match its checksum".to_string()))),
+                Ok(Record::CorruptHeader) => {
+                    let mut rest = Vec::new();
+                    if let Err(e) = self.reader.read_to_end(&mut rest) {
+                        return Some(Err(e.into()));
+                    }
+                    if !is_torn(&rest) {
+                        return Some(Err(Error::Corruption("Log record header does not match its checksum".to_string())));
+                    }
+                    warn!("Ignoring incomplete record at the end of the log");
+                    return None;
+                }
+                Err(e) => return Some(Err(e.into())),
+            };
         }
+
+        self.pending.pop_front().map(Ok)
     }
 }
 
@@ -146,9 +742,291 @@ impl Iterator for LogStoreIterator {
 mod tests {
     use super::*;
     #[test]
-    fn test_serialize() {
-        let x = "Hello\\\nWorld\t!\\n\n";
-        let y = deserialize(&serialize(x));
-        assert!(x == y, "Expected {} but got {}", x, y);
+    fn test_deserialize() {
+        let x = b"Hello\\\\\\nWorld\\t!\\\\n\\n\xff\xfe";
+        let y = deserialize(x);
+        let expected = b"Hello\\\nWorld\t!\\n\n\xff\xfe";
+        assert!(expected.as_slice() == y, "Expected {:?} but got {:?}", expected, y);
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let file_path = PathBuf::from("test_temp_write_batch.log");
+        let _ = std::fs::remove_file(&file_path);
+        let mut log = LogStore::init(file_path.to_owned()).unwrap();
+
+        log.set(b"a", b"0").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set(b"a", b"1").delete(b"b").set(b"\\B", b"+\t-");
+        log.write_batch(2, &batch).unwrap();
+
+        let expected = vec![
+            (b"a".to_vec(), Some(b"0".to_vec())),
+            (b"a".to_vec(), Some(b"1".to_vec())),
+            (b"b".to_vec(), None),
+            (b"\\B".to_vec(), Some(b"+\t-".to_vec())),
+        ];
+        assert_eq!(log.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), expected);
+
+        // A batch torn halfway through its record is dropped entirely
+        let intact_len = std::fs::metadata(&file_path).unwrap().len();
+        let mut batch = WriteBatch::new();
+        batch.set(b"c", b"1").set(b"d", b"1");
+        log.write_batch(3, &batch).unwrap();
+        log.writer.set_len(intact_len + 20).unwrap();
+        assert_eq!(log.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), expected);
+
+        let _ = std::fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let file_path = PathBuf::from("test_temp_checksum.log");
+        let _ = std::fs::remove_file(&file_path);
+        let mut log = LogStore::init(file_path.to_owned()).unwrap();
+
+        log.set(b"a", b"1").unwrap();
+        assert_eq!(log.get(b"a").unwrap(), Some(b"1".to_vec()));
+        let first_end = std::fs::metadata(&file_path).unwrap().len() as usize;
+        log.set(b"b", b"1").unwrap();
+
+        // Flip the value of the first record, which sits just before its checksum. Only a corrupt final record could
+        // be a torn append, so this one is an error.
+        let mut bytes = std::fs::read(&file_path).unwrap();
+        bytes[first_end - 5] = b'2';
+        std::fs::write(&file_path, &bytes).unwrap();
+
+        let error = log.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap_err();
+        assert!(matches!(error, Error::Corruption(_)), "Expected a corruption error but got {}", error);
+        assert!(log.get(b"a").is_err(), "Corrupt record should not be returned");
+
+        let _ = std::fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_recover() {
+        let file_path = PathBuf::from("test_temp_recover.log");
+        let _ = std::fs::remove_file(&file_path);
+        let mut log = LogStore::init(file_path.to_owned()).unwrap();
+
+        log.log_operation(1, b"a", Some(b"1")).unwrap().wait().unwrap();
+        let intact_len = std::fs::metadata(&file_path).unwrap().len();
+        log.recover().unwrap();
+        assert_eq!(std::fs::metadata(&file_path).unwrap().len(), intact_len, "Intact log should be left alone");
+
+        // A record cut off partway through its header
+        log.append(&[PUT, 2, 0]).unwrap().wait().unwrap();
+        log.recover().unwrap();
+        assert_eq!(std::fs::metadata(&file_path).unwrap().len(), intact_len);
+
+        // A final record whose bytes never fully made it to disk
+        log.log_operation(2, b"b", Some(b"2")).unwrap().wait().unwrap();
+        let mut bytes = std::fs::read(&file_path).unwrap();
+        let len = bytes.len();
+        bytes[len - 1] ^= 0xff;
+        std::fs::write(&file_path, &bytes).unwrap();
+        // Readers which cannot truncate the log skip over it instead
+        assert_eq!(read_log(&file_path).unwrap().collect::<Result<Vec<_>, _>>().unwrap(), vec![(b"a".to_vec(), Some(b"1".to_vec()))]);
+        log.recover().unwrap();
+        assert_eq!(std::fs::metadata(&file_path).unwrap().len(), intact_len);
+        let mut records = log.iter().unwrap();
+        assert_eq!(records.by_ref().count(), 1);
+        assert_eq!(records.sequence_number(), 1, "Sequence number should be that of the last intact record");
+
+        log.set(b"b", b"2").unwrap();
+        assert_eq!(log.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), vec![
+            (b"a".to_vec(), Some(b"1".to_vec())),
+            (b"b".to_vec(), Some(b"2".to_vec())),
+        ]);
+
+        let _ = std::fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_corrupt_length() {
+        let file_path = PathBuf::from("test_temp_corrupt_length.log");
+        let _ = std::fs::remove_file(&file_path);
+        let mut log = LogStore::init(file_path.to_owned()).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            log.set(key, b"1").unwrap();
+        }
+        drop(log);
+
+        // A damaged length in the middle of the log is reported instead of cutting off the records after it
+        let intact = std::fs::read(&file_path).unwrap();
+        let mut bytes = intact.to_owned();
+        bytes[FILE_HEADER_SIZE + 1 + 8 + 3] ^= 0x40;
+        std::fs::write(&file_path, &bytes).unwrap();
+        let mut log = LogStore::init(file_path.to_owned()).unwrap();
+        assert!(matches!(log.recover(), Err(Error::Corruption(_))));
+        assert_eq!(std::fs::read(&file_path).unwrap(), bytes, "Damaged log should be left as it was");
+        assert!(matches!(read_log(&file_path).unwrap().collect::<Result<Vec<_>, _>>(), Err(Error::Corruption(_))));
+
+        // Garbage in place of the last header can only be a torn append
+        let mut torn = intact.to_owned();
+        torn.extend([0xab; RECORD_HEADER_SIZE + 3]);
+        std::fs::write(&file_path, &torn).unwrap();
+        assert_eq!(read_log(&file_path).unwrap().count(), 3);
+        log.recover().unwrap();
+        assert_eq!(std::fs::read(&file_path).unwrap(), intact);
+
+        let _ = std::fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_migrate_unchecked_log() {
+        let file_path = PathBuf::from("test_temp_migrate_unchecked.log");
+        let _ = std::fs::remove_file(&file_path);
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(UNCHECKED_VERSION.to_ne_bytes());
+        let mut record = vec![PUT];
+        record.extend(5u64.to_ne_bytes());
+        let mut payload = Vec::new();
+        encode_operation(&mut payload, b"a", Some(b"1")).unwrap();
+        record.extend((payload.len() as u32).to_ne_bytes());
+        record.extend(payload);
+        record.extend(crc32(&record).to_ne_bytes());
+        bytes.extend(&record);
+        bytes.extend(&record[..7]);
+        std::fs::write(&file_path, &bytes).unwrap();
+
+        // Readers take older logs as they are, and a writable open rewrites them with the same sequence numbers
+        assert_eq!(read_log(&file_path).unwrap().count(), 1);
+        let log = LogStore::init(file_path.to_owned()).unwrap();
+        assert!(!std::fs::read(&file_path).unwrap().starts_with(&bytes[..FILE_HEADER_SIZE]), "Log should be rewritten in the current format");
+        let mut records = log.iter().unwrap();
+        assert_eq!(records.by_ref().collect::<Result<Vec<_>, _>>().unwrap(), vec![(b"a".to_vec(), Some(b"1".to_vec()))]);
+        assert_eq!(records.sequence_number(), 5);
+
+        let _ = std::fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let file_path = PathBuf::from("test_temp_sync_policy.log");
+        let _ = std::fs::remove_file(&file_path);
+        let mut log = LogStore::init(file_path.to_owned()).unwrap();
+        let syncs = |log: &LogStore| log.group_commit.state.lock().unwrap().syncs;
+
+        log.set(b"a", b"1").unwrap();
+        assert_eq!(syncs(&log), 0, "Log should not be synced by default");
+
+        log.set_sync_policy(SyncPolicy::Always);
+        log.set(b"a", b"1").unwrap();
+        log.set(b"a", b"1").unwrap();
+        assert_eq!(syncs(&log), 2);
+
+        // Each of these records is 31 bytes long
+        log.set_sync_policy(SyncPolicy::Bytes(100));
+        for _ in 0..8 {
+            log.set(b"a", b"1").unwrap();
+        }
+        assert_eq!(syncs(&log), 4, "Log should sync after every fourth record");
+
+        log.set_sync_policy(SyncPolicy::Interval(std::time::Duration::from_secs(3600)));
+        log.set(b"a", b"1").unwrap();
+        assert_eq!(syncs(&log), 4);
+
+        // Once writes stop, the last of them are still synced when the interval runs out
+        log.set_sync_policy(SyncPolicy::Interval(std::time::Duration::from_millis(20)));
+        log.set(b"a", b"1").unwrap();
+        let appended = log.group_commit.appended.load(Ordering::SeqCst);
+        let start = Instant::now();
+        while log.group_commit.state.lock().unwrap().synced_to < appended {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5), "Idle log was never synced");
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert_eq!(syncs(&log), 5);
+        drop(log);
+
+        let _ = std::fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let file_path = PathBuf::from("test_temp_group_commit.log");
+        let _ = std::fs::remove_file(&file_path);
+        let log = LogStore::init(file_path.to_owned()).unwrap();
+        let group_commit = log.group_commit.to_owned();
+
+        let writers = (0..16).map(|_| {
+            let group_commit = group_commit.to_owned();
+            std::thread::spawn(move || {
+                let position = group_commit.appended.fetch_add(1, Ordering::SeqCst) + 1;
+                group_commit.sync_to(position).unwrap();
+                assert!(group_commit.state.lock().unwrap().synced_to >= position, "Writer returned before its write was synced");
+            })
+        }).collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let syncs = group_commit.state.lock().unwrap().syncs;
+        assert_eq!(group_commit.state.lock().unwrap().synced_to, 16);
+        assert!(syncs <= 16, "Writers should never need more than one sync each, got {}", syncs);
+
+        // Writes already covered by an earlier sync do not sync again
+        group_commit.sync_to(10).unwrap();
+        assert_eq!(group_commit.state.lock().unwrap().syncs, syncs);
+
+        // A writer which panicked while holding the state fails everyone after it instead of panicking them too
+        let poisoner = group_commit.to_owned();
+        assert!(std::thread::spawn(move || {
+            let _state = poisoner.state.lock().unwrap();
+            panic!("Writer died mid sync");
+        }).join().is_err());
+        assert!(matches!(group_commit.sync_to(17), Err(Error::Internal(_))));
+        drop(log);
+
+        let _ = std::fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_migrate_text_log() {
+        let file_path = PathBuf::from("test_temp_migrate.log");
+        let _ = std::fs::remove_file(&file_path);
+
+        let mut text = b"legacy\tvalue\n\\B\t+\tk\\tey\t1\t-\tlegacy\n".to_vec();
+        text.extend(format!("\\C{:08x}\ta\t1\n", crc32(b"a\t1")).as_bytes());
+        text.extend(b"torn\t");
+        std::fs::write(&file_path, &text).unwrap();
+
+        let mut log = LogStore::init(file_path.to_owned()).unwrap();
+        log.recover().unwrap();
+        log.delete(b"a").unwrap();
+
+        assert!(std::fs::read(&file_path).unwrap().starts_with(MAGIC), "Log should be rewritten in the binary format");
+        assert!(!file_path.with_extension("migrate").exists());
+        assert_eq!(log.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), vec![
+            (b"legacy".to_vec(), Some(b"value".to_vec())),
+            (b"k\tey".to_vec(), Some(b"1".to_vec())),
+            (b"legacy".to_vec(), None),
+            (b"a".to_vec(), Some(b"1".to_vec())),
+            (b"a".to_vec(), None),
+        ]);
+
+        let _ = std::fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_delete() {
+        let file_path = PathBuf::from("test_temp_delete.log");
+        let _ = std::fs::remove_file(&file_path);
+        let mut log = LogStore::init(file_path.to_owned()).unwrap();
+
+        log.set(b"a", b"1").unwrap();
+        log.set(b"b", b"2").unwrap();
+        log.delete(b"a").unwrap();
+
+        assert_eq!(log.get(b"a").unwrap(), None);
+        assert_eq!(log.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(log.iter().unwrap().collect::<Result<Vec<_>, _>>().unwrap(), vec![
+            (b"a".to_vec(), Some(b"1".to_vec())),
+            (b"b".to_vec(), Some(b"2".to_vec())),
+            (b"a".to_vec(), None),
+        ]);
+
+        let _ = std::fs::remove_file(file_path);
     }
-}
\ No newline at end of file
+}
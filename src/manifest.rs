@@ -0,0 +1,422 @@
+use std::{cmp::Reverse, fs::{self, File, OpenOptions}, io::{self, Write}, path::Path};
+
+use log::warn;
+
+use crate::{checksum::crc32, log_store::{put_bytes, take_bytes}, segment_store::{load_from_file, sync_directory, SegmentStore}, Error, SetResult};
+
+pub const MANIFEST_FILE: &str = "MANIFEST";
+// Leads the manifest, followed by the format version.
+const MAGIC: &[u8; 8] = b"zdb\0man\0";
+const FORMAT_VERSION: u32 = 3;
+// Before segments had levels, which left every one of them in level 0.
+const UNLEVELED_VERSION: u32 = 1;
+// Before edits recorded the last sequence number, which was then only found in the log.
+const UNSEQUENCED_VERSION: u32 = 2;
+const FILE_HEADER_SIZE: usize = MAGIC.len() + 4;
+
+const ADD: u8 = 1;
+const REMOVE: u8 = 2;
+const LAST_SEQUENCE: u8 = 3;
+
+// A live segment as recorded in the manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentMeta {
+    pub file_name: String,
+    pub sequence_number: usize,
+    pub level: usize,
+    pub size: u64,
+    // Both empty for a segment holding no keys
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+}
+
+impl SegmentMeta {
+    pub fn of(segment: &SegmentStore, level: usize) -> Result<SegmentMeta, Error> {
+        let (smallest_key, largest_key) = segment.get_key_range()?.unwrap_or_default();
+        Ok(SegmentMeta {
+            file_name: segment.get_file_name().to_owned(),
+            sequence_number: segment.get_sequence_number(),
+            level,
+            size: segment.get_size()?,
+            smallest_key,
+            largest_key,
+        })
+    }
+}
+
+// Segments added to and removed from the live set together, as the outcome of one flush or compaction.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct VersionEdit {
+    pub added: Vec<SegmentMeta>,
+    pub removed: Vec<String>,
+    // Sequence number of the newest write held by the segments, so it outlives the log a flush empties
+    pub last_sequence: Option<u64>,
+}
+
+// An append-only log of version edits, replayed on open to find the live segments. Segment files it does not list
+// are left over from a flush or compaction which never completed.
+pub struct Manifest {
+    writer: Option<File>, // `None` for a database opened read-only
+    live: Vec<SegmentMeta>, // Ordered from oldest to newest, deepest level first
+}
+
+impl Manifest {
+    // Starts a new manifest holding only the given segments and the last sequence number, replacing any old one in a
+    // single rename.
+    pub fn create(directory: &Path, live: Vec<SegmentMeta>, last_sequence: u64) -> Result<Manifest, Error> {
+        let temp_path = directory.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = File::create(&temp_path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&FORMAT_VERSION.to_ne_bytes())?;
+        file.write_all(&encode_record(&VersionEdit { added: live.to_owned(), removed: Vec::new(), last_sequence: Some(last_sequence) })?)?;
+        file.sync_all()?;
+
+        let file_path = directory.join(MANIFEST_FILE);
+        fs::rename(&temp_path, &file_path)?;
+        sync_directory(&file_path)?;
+
+        let mut manifest = Manifest {
+            writer: Some(OpenOptions::new().append(true).open(&file_path)?),
+            live: Vec::new(),
+        };
+        replay(&mut manifest.live, VersionEdit { added: live, ..VersionEdit::default() });
+        Ok(manifest)
+    }
+
+    // Holds the segments without a file to record edits in, so the manifest on disk is left as it is.
+    pub fn read_only(mut live: Vec<SegmentMeta>) -> Manifest {
+        live.sort_by_key(|meta| (Reverse(meta.level), meta.sequence_number));
+        Manifest { writer: None, live }
+    }
+
+    // Durably records the edit before it takes effect.
+    pub fn apply(&mut self, edit: VersionEdit) -> SetResult {
+        let writer = self.writer.as_mut().ok_or_else(|| Error::NotSupported("Manifest is read-only".to_string()))?;
+        writer.write_all(&encode_record(&edit)?)?;
+        writer.sync_data()?;
+        replay(&mut self.live, edit);
+        Ok(())
+    }
+
+    pub fn live(&self) -> &[SegmentMeta] {
+        &self.live
+    }
+}
+
+// Returns the most of the inclusive key ranges which cover any single key.
+pub fn max_overlap<'a>(ranges: impl Iterator<Item = (&'a [u8], &'a [u8])>) -> usize {
+    // Sweep over range boundaries, opening a range before closing any other at the same key
+    let mut boundaries = ranges.flat_map(|(smallest, largest)| [(smallest, 0, 1), (largest, 1, -1)]).collect::<Vec<_>>();
+    boundaries.sort();
+
+    let mut depth: i64 = 0;
+    let mut deepest = 0;
+    for (_, _, change) in boundaries {
+        depth += change;
+        deepest = deepest.max(depth);
+    }
+    deepest as usize
+}
+
+fn replay(live: &mut Vec<SegmentMeta>, edit: VersionEdit) {
+    live.retain(|meta| !edit.removed.contains(&meta.file_name));
+    live.extend(edit.added);
+    live.sort_by_key(|meta| (Reverse(meta.level), meta.sequence_number));
+}
+
+// Returns the live segments and the last sequence number recorded, or `None` for a database written before it had a
+// manifest.
+pub fn read_manifest(directory: &Path) -> Result<Option<(Vec<SegmentMeta>, u64)>, Error> {
+    let file_path = directory.join(MANIFEST_FILE);
+    let bytes = match fs::read(&file_path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::Io(e)),
+    };
+
+    if parse_text_manifest(&bytes).is_some() {
+        return Err(Error::NotSupported(format!("Manifest {} is in the text format, open the database writable to migrate it", file_path.display())));
+    }
+    if bytes.len() < FILE_HEADER_SIZE || !bytes.starts_with(MAGIC) {
+        return Err(Error::Corruption(format!("Manifest {} does not start with a manifest header", file_path.display())));
+    }
+    let version = u32::from_ne_bytes(bytes[MAGIC.len()..FILE_HEADER_SIZE].try_into().unwrap());
+    if ![FORMAT_VERSION, UNSEQUENCED_VERSION, UNLEVELED_VERSION].contains(&version) {
+        return Err(Error::Corruption(format!("Manifest {} has unknown format version {}", file_path.display(), version)));
+    }
+
+    let mut live = Vec::new();
+    let mut last_sequence = 0;
+    let mut input = &bytes[FILE_HEADER_SIZE..];
+    while !input.is_empty() {
+        // An edit is written with a single append, so only the last one can be torn, and it never took effect
+        if input.len() < 8 {
+            warn!("Ignoring incomplete edit at the end of the manifest");
+            break;
+        }
+        let len = u32::from_ne_bytes(input[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_ne_bytes(input[4..8].try_into().unwrap());
+        if input.len() < 8 + len {
+            warn!("Ignoring incomplete edit at the end of the manifest");
+            break;
+        }
+        let payload = &input[8..8 + len];
+        if crc32(payload) != checksum {
+            return Err(Error::Corruption("Manifest edit does not match its checksum".to_string()));
+        }
+        let edit = decode_edit(payload, version).ok_or_else(|| Error::Corruption("Manifest holds a malformed edit".to_string()))?;
+        last_sequence = last_sequence.max(edit.last_sequence.unwrap_or(0));
+        replay(&mut live, edit);
+        input = &input[8 + len..];
+    }
+
+    Ok(Some((live, last_sequence)))
+}
+
+// Rewrites a manifest left in the text format, which listed the live segment files one per line, into the binary one,
+// taking every segment to be in level 0. Returns whether there was one to migrate.
+pub fn migrate_text_manifest(directory: &Path) -> Result<bool, Error> {
+    let bytes = match fs::read(directory.join(MANIFEST_FILE)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    let file_names = match parse_text_manifest(&bytes) {
+        Some(file_names) => file_names,
+        None => return Ok(false),
+    };
+
+    let mut live = Vec::new();
+    for file_name in file_names {
+        let file_path = directory.join(file_name);
+        if !file_path.exists() {
+            return Err(Error::NotFound(format!("Segment {} listed in the manifest is missing", file_name)));
+        }
+        live.push(SegmentMeta::of(&load_from_file(file_path)?, 0)?);
+    }
+    warn!("Migrating manifest in {} from the text format", directory.display());
+    Manifest::create(directory, live, 0)?;
+    Ok(true)
+}
+
+// The text format was replaced in a single rename, so it is either empty or every line names a segment file.
+fn parse_text_manifest(bytes: &[u8]) -> Option<Vec<&str>> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let file_names = text.lines().collect::<Vec<_>>();
+    let is_file_name = |line: &&str| line.ends_with(".seg") && !line.contains(['/', '\\', '\0']);
+    (text.is_empty() || (text.ends_with('\n') && file_names.iter().all(is_file_name))).then_some(file_names)
+}
+
+// Length and checksum, followed by every added and removed segment and the last sequence number.
+fn encode_record(edit: &VersionEdit) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    for meta in edit.added.iter() {
+        payload.push(ADD);
+        put_bytes(&mut payload, meta.file_name.as_bytes())?;
+        payload.extend((meta.sequence_number as u64).to_ne_bytes());
+        payload.extend((meta.level as u64).to_ne_bytes());
+        payload.extend(meta.size.to_ne_bytes());
+        put_bytes(&mut payload, &meta.smallest_key)?;
+        put_bytes(&mut payload, &meta.largest_key)?;
+    }
+    for file_name in edit.removed.iter() {
+        payload.push(REMOVE);
+        put_bytes(&mut payload, file_name.as_bytes())?;
+    }
+    if let Some(last_sequence) = edit.last_sequence {
+        payload.push(LAST_SEQUENCE);
+        payload.extend(last_sequence.to_ne_bytes());
+    }
+
+    let mut record = u32::try_from(payload.len()).map_err(|_| Error::InvalidArgument("Manifest edit is too large".to_string()))?.to_ne_bytes().to_vec();
+    record.extend(crc32(&payload).to_ne_bytes());
+    record.extend(payload);
+    Ok(record)
+}
+
+fn decode_edit(mut payload: &[u8], version: u32) -> Option<VersionEdit> {
+    let mut edit = VersionEdit::default();
+    while let Some((&tag, rest)) = payload.split_first() {
+        payload = rest;
+        match tag {
+            ADD => {
+                let file_name = String::from_utf8(take_bytes(&mut payload)?).ok()?;
+                let sequence_number = take_u64(&mut payload)? as usize;
+                let (level, size) = match version {
+                    UNLEVELED_VERSION => (0, 0),
+                    _ => (take_u64(&mut payload)? as usize, take_u64(&mut payload)?),
+                };
+                edit.added.push(SegmentMeta {
+                    file_name,
+                    sequence_number,
+                    level,
+                    size,
+                    smallest_key: take_bytes(&mut payload)?,
+                    largest_key: take_bytes(&mut payload)?,
+                });
+            }
+            REMOVE => edit.removed.push(String::from_utf8(take_bytes(&mut payload)?).ok()?),
+            LAST_SEQUENCE if version == FORMAT_VERSION => edit.last_sequence = Some(take_u64(&mut payload)?),
+            _ => return None,
+        }
+    }
+    Some(edit)
+}
+
+fn take_u64(payload: &mut &[u8]) -> Option<u64> {
+    if payload.len() < 8 {
+        return None;
+    }
+    let (value, rest) = payload.split_at(8);
+    *payload = rest;
+    Some(u64::from_ne_bytes(value.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::SegmentOptions;
+    use std::path::PathBuf;
+
+    // The most live segments whose key ranges cover any single key, leaving out those holding no keys.
+    fn read_amplification(manifest: &Manifest) -> usize {
+        max_overlap(manifest.live().iter()
+            .filter(|meta| !meta.smallest_key.is_empty() || !meta.largest_key.is_empty())
+            .map(|meta| (meta.smallest_key.as_slice(), meta.largest_key.as_slice())))
+    }
+
+    fn meta(file_name: &str, sequence_number: usize) -> SegmentMeta {
+        SegmentMeta {
+            file_name: file_name.to_string(),
+            sequence_number,
+            level: 0,
+            size: 0,
+            smallest_key: b"a".to_vec(),
+            largest_key: b"z\xff".to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_manifest() {
+        let directory = PathBuf::from("/tmp/zdb_test_manifest");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        assert_eq!(read_manifest(&directory).unwrap(), None);
+
+        let mut manifest = Manifest::create(&directory, vec![meta("b.seg", 2), meta("a.seg", 1)], 10).unwrap();
+        assert_eq!(manifest.live(), [meta("a.seg", 1), meta("b.seg", 2)]);
+
+        manifest.apply(VersionEdit {
+            added: vec![meta("c.seg", 1)],
+            removed: vec!["a.seg".to_string(), "b.seg".to_string()],
+            ..VersionEdit::default()
+        }).unwrap();
+        manifest.apply(VersionEdit { added: vec![meta("d.seg", 3)], removed: Vec::new(), last_sequence: Some(20) }).unwrap();
+        assert_eq!(manifest.live(), [meta("c.seg", 1), meta("d.seg", 3)]);
+        assert_eq!(read_manifest(&directory).unwrap().unwrap(), (manifest.live().to_vec(), 20));
+
+        // An edit torn partway through its append never took effect
+        let intact = fs::read(directory.join(MANIFEST_FILE)).unwrap();
+        let mut torn = intact.to_owned();
+        torn.extend(&encode_record(&VersionEdit { added: vec![meta("e.seg", 4)], last_sequence: Some(30), ..VersionEdit::default() }).unwrap()[..10]);
+        fs::write(directory.join(MANIFEST_FILE), &torn).unwrap();
+        assert_eq!(read_manifest(&directory).unwrap().unwrap(), (manifest.live().to_vec(), 20));
+
+        // A flipped bit in a complete edit is reported
+        let mut corrupt = intact.to_owned();
+        let len = corrupt.len();
+        corrupt[len - 1] ^= 1;
+        fs::write(directory.join(MANIFEST_FILE), &corrupt).unwrap();
+        assert!(read_manifest(&directory).is_err());
+
+        // Deeper levels hold older data, whatever their sequence numbers
+        let mut manifest = Manifest::create(&directory, manifest.live().to_vec(), 20).unwrap();
+        manifest.apply(VersionEdit {
+            added: vec![SegmentMeta { level: 1, ..meta("d.seg", 3) }],
+            removed: vec!["d.seg".to_string()],
+            ..VersionEdit::default()
+        }).unwrap();
+        assert_eq!(manifest.live(), [SegmentMeta { level: 1, ..meta("d.seg", 3) }, meta("c.seg", 1)]);
+        assert_eq!(read_manifest(&directory).unwrap().unwrap(), (manifest.live().to_vec(), 20));
+
+        // Manifests written before levels leave every segment in level 0
+        let mut unleveled = MAGIC.to_vec();
+        unleveled.extend(UNLEVELED_VERSION.to_ne_bytes());
+        let mut payload = vec![ADD];
+        put_bytes(&mut payload, b"e.seg").unwrap();
+        payload.extend(4u64.to_ne_bytes());
+        put_bytes(&mut payload, b"a").unwrap();
+        put_bytes(&mut payload, b"b").unwrap();
+        unleveled.extend((payload.len() as u32).to_ne_bytes());
+        unleveled.extend(crc32(&payload).to_ne_bytes());
+        unleveled.extend(payload);
+        fs::write(directory.join(MANIFEST_FILE), &unleveled).unwrap();
+        assert_eq!(read_manifest(&directory).unwrap().unwrap(), (vec![SegmentMeta {
+            smallest_key: b"a".to_vec(),
+            largest_key: b"b".to_vec(),
+            ..meta("e.seg", 4)
+        }], 0));
+
+        // Manifests written before edits recorded the last sequence number leave it to the log
+        let mut unsequenced = MAGIC.to_vec();
+        unsequenced.extend(UNSEQUENCED_VERSION.to_ne_bytes());
+        unsequenced.extend(encode_record(&VersionEdit { added: vec![meta("e.seg", 4)], ..VersionEdit::default() }).unwrap());
+        fs::write(directory.join(MANIFEST_FILE), &unsequenced).unwrap();
+        assert_eq!(read_manifest(&directory).unwrap().unwrap(), (vec![meta("e.seg", 4)], 0));
+
+        assert_eq!(read_amplification(&manifest), 2);
+        let disjoint = |file_name: &str, sequence_number, smallest: &[u8], largest: &[u8]| SegmentMeta {
+            smallest_key: smallest.to_vec(),
+            largest_key: largest.to_vec(),
+            ..meta(file_name, sequence_number)
+        };
+        let manifest = Manifest::create(&directory, vec![
+            disjoint("f.seg", 5, b"a", b"c"),
+            disjoint("g.seg", 6, b"c", b"e"),
+            disjoint("h.seg", 7, b"f", b"g"),
+            disjoint("i.seg", 8, b"b", b"b"),
+            disjoint("j.seg", 9, b"", b""),
+        ], 0).unwrap();
+        assert_eq!(read_amplification(&manifest), 2, "Ranges meeting at a key should both cover it");
+
+        // Anything else without a header is reported rather than taken for an empty database
+        fs::write(directory.join(MANIFEST_FILE), b"zdb\0man").unwrap();
+        assert!(matches!(read_manifest(&directory), Err(Error::Corruption(_))));
+        let mut unknown = MAGIC.to_vec();
+        unknown.extend(99u32.to_ne_bytes());
+        fs::write(directory.join(MANIFEST_FILE), &unknown).unwrap();
+        assert!(matches!(read_manifest(&directory), Err(Error::Corruption(_))));
+        assert!(!migrate_text_manifest(&directory).unwrap());
+
+        assert!(!directory.join("MANIFEST.tmp").exists());
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_migrate_text_manifest() {
+        let directory = PathBuf::from("/tmp/zdb_test_manifest_migrate");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        let entries = [(b"a".to_vec(), Some(b"1".to_vec())), (b"b".to_vec(), None)];
+        let segment = SegmentStore::create_from_iterator(directory.join("a.seg"), 1, SegmentOptions::default(), entries.into_iter()).unwrap();
+
+        // The text format is only read once it has been migrated
+        fs::write(directory.join(MANIFEST_FILE), "a.seg\n").unwrap();
+        assert!(matches!(read_manifest(&directory), Err(Error::NotSupported(_))));
+        assert!(migrate_text_manifest(&directory).unwrap());
+        assert_eq!(read_manifest(&directory).unwrap().unwrap(), (vec![SegmentMeta::of(&segment, 0).unwrap()], 0));
+        assert!(!migrate_text_manifest(&directory).unwrap());
+
+        // An empty one listed no segments
+        fs::write(directory.join(MANIFEST_FILE), "").unwrap();
+        assert!(migrate_text_manifest(&directory).unwrap());
+        assert_eq!(read_manifest(&directory).unwrap().unwrap(), (Vec::new(), 0));
+
+        fs::write(directory.join(MANIFEST_FILE), "missing.seg\n").unwrap();
+        assert!(matches!(migrate_text_manifest(&directory), Err(Error::NotFound(_))));
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
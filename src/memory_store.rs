@@ -1,49 +1,149 @@
-use std::collections::BTreeMap;
+use std::{borrow::Borrow, collections::BTreeMap, ops::Bound, sync::{atomic::{AtomicUsize, Ordering}, PoisonError, RwLock, RwLockReadGuard}};
 
-use super::{Storage, SetResult, GetResult};
+use super::{Storage, SetResult, GetResult, DeleteResult};
+use crate::{cursor::EntryCursor, Entry, Error};
+
+// Reads at this sequence number see every write.
+pub const LATEST: u64 = u64::MAX;
+
+// A value and the sequence number of the write which made it. `None` when the key has been deleted, so the tombstone
+// shadows older segments.
+type Version = (u64, Option<Vec<u8>>);
+
+// Each key holds its versions from oldest to newest, so a snapshot sharing the memtable reads it as it was at its own
+// sequence number while writes carry on.
 pub struct MemoryStore {
-    map: BTreeMap<String, String>,
-    memory_usage: usize
+    map: RwLock<BTreeMap<Vec<u8>, Vec<Version>>>,
+    memory_usage: AtomicUsize,
 }
 
+// Writes made through `Storage` are taken to come before every snapshot, and replace the versions before them.
 impl Storage for MemoryStore {
-    fn set(&mut self, key: &str, value: &str) -> SetResult {
-        let key_len = key.len();
-        let value_len = value.len();
-        match self.map.insert(key.to_owned(), value.to_owned()) {
-            Some(v) => {
-                self.memory_usage += value_len;
-                self.memory_usage -= v.len();
-            }
-            None => {
-                self.memory_usage += value_len + key_len;
-            }
-        }
-        return Ok(())
+    fn set(&mut self, key: &[u8], value: &[u8]) -> SetResult {
+        self.insert(0, key, Some(value), false);
+        Ok(())
     }
 
-    fn get(&self, key: &str) -> GetResult {
-        match self.map.get(key) {
-            Some(value) => Ok(Some(value.to_owned())),
-            None => Ok(None)
-        }
+    fn get(&self, key: &[u8]) -> GetResult {
+        Ok(self.get_entry(key, LATEST).flatten())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> DeleteResult {
+        self.insert(0, key, None, false);
+        Ok(())
     }
 } 
 
 impl MemoryStore {
     pub fn new() -> MemoryStore {
         MemoryStore {
-            map: BTreeMap::new(),
-            memory_usage: 0
+            map: RwLock::new(BTreeMap::new()),
+            memory_usage: AtomicUsize::new(0),
         }
     }
 
+    // Counts every version held, not only the latest.
     pub fn get_memory_usage(&self) -> usize {
-        self.memory_usage
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    // Returns `Some(None)` if the key is shadowed by a tombstone, and `None` if the key is unknown, as of the sequence number.
+    pub fn get_entry(&self, key: &[u8], sequence_number: u64) -> Option<Option<Vec<u8>>> {
+        self.read().get(key).and_then(|versions| visible(versions, sequence_number)).cloned()
+    }
+
+    // Copies out the entries within the range as they were at the sequence number.
+    pub fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>), sequence_number: u64) -> Vec<Entry> {
+        self.read().range(range)
+            .filter_map(|(k, versions)| Some((k.to_owned(), visible(versions, sequence_number)?.to_owned())))
+            .collect()
+    }
+
+    // Hands the latest version of every key, in key order, to `f`.
+    pub fn with_latest<R>(&self, f: impl FnOnce(&mut dyn Iterator<Item = Entry>) -> R) -> R {
+        let map = self.read();
+        f(&mut map.iter().filter_map(|(k, versions)| Some((k.to_owned(), versions.last()?.1.to_owned()))))
+    }
+
+    // Adds a version of the key written at the sequence number. The versions before it are dropped unless `keep` is set,
+    // for snapshots which may still read them.
+    pub fn insert(&self, sequence_number: u64, key: &[u8], value: Option<&[u8]>, keep: bool) {
+        let mut map = self.map.write().unwrap_or_else(PoisonError::into_inner);
+        let versions = map.entry(key.to_owned()).or_default();
+        let added = value.map_or(0, <[u8]>::len) + if versions.is_empty() { key.len() } else { 0 };
+        let removed = match keep {
+            true => 0,
+            false => versions.drain(..).map(|(_, v)| v.map_or(0, |v| v.len())).sum(),
+        };
+        versions.push((sequence_number, value.map(<[u8]>::to_owned)));
+        self.memory_usage.fetch_add(added, Ordering::Relaxed);
+        self.memory_usage.fetch_sub(removed, Ordering::Relaxed);
+    }
+
+    // Writers never leave the map half changed, so one which panicked does not keep readers out.
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<Vec<u8>, Vec<Version>>> {
+        self.map.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// The newest of the versions written at or before the sequence number.
+fn visible(versions: &[Version], sequence_number: u64) -> Option<&Option<Vec<u8>>> {
+    versions.iter().rev().find(|(s, _)| *s <= sequence_number).map(|(_, v)| v)
+}
+
+// Holds a copy of the current entry rather than a reference into the map, so the cursor can own the store it walks
+// while writes carry on.
+pub struct MemoryCursor<M: Borrow<MemoryStore>> {
+    store: M,
+    sequence_number: u64,
+    current: Option<Entry>,
+}
+
+impl<M: Borrow<MemoryStore>> MemoryCursor<M> {
+    // Sees the entries as they were at the sequence number.
+    pub fn new(store: M, sequence_number: u64) -> MemoryCursor<M> {
+        MemoryCursor {
+            store,
+            sequence_number,
+            current: None,
+        }
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
-        self.map.iter()
+    fn settle(&mut self, range: (Bound<&[u8]>, Bound<&[u8]>), forward: bool) {
+        let map = self.store.borrow().read();
+        let mut entries = map.range::<[u8], _>(range).filter_map(|(k, versions)| Some((k, visible(versions, self.sequence_number)?)));
+        let entry = if forward { entries.next() } else { entries.next_back() };
+        self.current = entry.map(|(k, v)| (k.to_owned(), v.to_owned()));
+    }
+}
+
+impl<M: Borrow<MemoryStore>> EntryCursor for MemoryCursor<M> {
+    fn seek(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.settle((Bound::Included(key), Bound::Unbounded), true);
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.settle((Bound::Unbounded, Bound::Included(key)), false);
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<(), Error> {
+        if let Some((key, _)) = self.current.take() {
+            self.settle((Bound::Excluded(&key), Bound::Unbounded), true);
+        }
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<(), Error> {
+        if let Some((key, _)) = self.current.take() {
+            self.settle((Bound::Unbounded, Bound::Excluded(&key)), false);
+        }
+        Ok(())
+    }
+
+    fn entry(&self) -> Option<(&[u8], Option<&[u8]>)> {
+        self.current.as_ref().map(|(k, v)| (k.as_slice(), v.as_deref()))
     }
 }
 
@@ -55,14 +155,14 @@ mod tests {
     fn test_set_get() {
         let mut store = MemoryStore::new();
 
-        let k = "key";
-        let v = "value";
+        let k = b"key";
+        let v = b"value";
         
         match store.set(k, v) {
             Ok(_) => {
-                match store.get("key") {
+                match store.get(b"key") {
                     Ok(_) => {
-                        match store.get("key").unwrap() {
+                        match store.get(b"key").unwrap() {
                             Some(value) => {
                                 assert_eq!(value, v);
                             }
@@ -90,22 +190,66 @@ mod tests {
 
         let mut store = MemoryStore::new();
 
-        let k = "key";
-        let v = "value";
+        let k = b"key";
+        let v = b"value";
 
         let expected_memory_usage = k.len() + v.len();
 
-        assert!(store.memory_usage == 0, "Memory usage should start empty.");
+        assert!(store.get_memory_usage() == 0, "Memory usage should start empty.");
         assert!(store.set(k, v).is_ok(), "Set failed");
-        assert!(store.memory_usage == expected_memory_usage, "Memory usage after first set is not correct! expected: {} got: {}", expected_memory_usage, store.memory_usage);
+        assert!(store.get_memory_usage() == expected_memory_usage, "Memory usage after first set is not correct! expected: {} got: {}", expected_memory_usage, store.get_memory_usage());
 
 
-        let v = "a different value ";
+        let v = b"a different value ";
 
         let expected_memory_usage = k.len() + v.len();
 
         assert!(store.set(k, v).is_ok(), "Set failed");
-        assert!(store.memory_usage == expected_memory_usage, "Memory usage after second set is not correct! expected: {} got: {}", expected_memory_usage, store.memory_usage);
+        assert!(store.get_memory_usage() == expected_memory_usage, "Memory usage after second set is not correct! expected: {} got: {}", expected_memory_usage, store.get_memory_usage());
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut store = MemoryStore::new();
+
+        assert!(store.set(b"key", b"value").is_ok(), "Set failed");
+        assert!(store.delete(b"key").is_ok(), "Delete failed");
+
+        assert_eq!(store.get(b"key").unwrap(), None, "Deleted key should not be returned");
+        assert_eq!(store.get_entry(b"key", LATEST), Some(None), "Deleted key should be shadowed by a tombstone");
+        assert_eq!(store.get_entry(b"missing", LATEST), None, "Unknown key should not have an entry");
+        assert!(store.get_memory_usage() == "key".len(), "Memory usage after delete is not correct! got: {}", store.get_memory_usage());
+    }
+
+    #[test]
+    fn test_versions() {
+        let store = MemoryStore::new();
+        store.insert(1, b"key", Some(b"one"), true);
+        store.insert(2, b"key", None, true);
+        store.insert(3, b"key", Some(b"three"), true);
+        store.insert(3, b"other", Some(b"three"), true);
+
+        // Reads see the newest version at or before their sequence number, and nothing before the first
+        assert_eq!(store.get_entry(b"key", 0), None);
+        assert_eq!(store.get_entry(b"key", 1), Some(Some(b"one".to_vec())));
+        assert_eq!(store.get_entry(b"key", 2), Some(None));
+        assert_eq!(store.get_entry(b"key", LATEST), Some(Some(b"three".to_vec())));
+        assert_eq!(store.range((Bound::Unbounded, Bound::Unbounded), 2), vec![(b"key".to_vec(), None)]);
+        assert_eq!(store.get_memory_usage(), "key".len() + "one".len() + "three".len() + "other".len() + "three".len());
+
+        let mut cursor = MemoryCursor::new(&store, 1);
+        cursor.seek(b"a").unwrap();
+        assert_eq!(cursor.entry(), Some((&b"key"[..], Some(&b"one"[..]))));
+        cursor.next().unwrap();
+        assert_eq!(cursor.entry(), None, "A key first written later should not be seen");
+
+        // Versions no snapshot needs are dropped
+        store.insert(4, b"key", Some(b"four"), false);
+        assert_eq!(store.get_entry(b"key", 3), None);
+        assert_eq!(store.get_memory_usage(), "key".len() + "four".len() + "other".len() + "three".len());
+        store.with_latest(|entries| {
+            assert_eq!(entries.collect::<Vec<_>>(), vec![(b"key".to_vec(), Some(b"four".to_vec())), (b"other".to_vec(), Some(b"three".to_vec()))]);
+        });
     }
 
-}
\ No newline at end of file
+}
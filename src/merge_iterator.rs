@@ -0,0 +1,105 @@
+use std::iter::Peekable;
+
+use crate::{Entry, Error};
+
+// Merges iterators which each yield keys in sorted order into a single sorted iterator.
+// Iterators are ordered from oldest to newest, and duplicate keys are resolved by taking the newest entry.
+// An error from any iterator is passed on as soon as it is reached.
+pub struct MergeIterator<I: Iterator<Item = Result<Entry, Error>>> {
+    iterators: Vec<Peekable<I>>,
+}
+
+impl<I: Iterator<Item = Result<Entry, Error>>> MergeIterator<I> {
+    pub fn new(iterators: impl IntoIterator<Item = I>) -> MergeIterator<I> {
+        MergeIterator {
+            iterators: iterators.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<Entry, Error>>> Iterator for MergeIterator<I> {
+    type Item = Result<Entry, Error>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        // Pop the current minimum key across all the iterators, while resolving duplicates.
+        let mut min_element: Option<Entry> = None;
+        let mut min_iter_index = None;
+        // Consider iterators from newest to oldest
+        for i in (0..self.iterators.len()).rev() {
+            let iter = &mut self.iterators[i];
+            let curr_element = match iter.peek() {
+                Some(Ok(element)) => element,
+                Some(Err(_)) => return iter.next(),
+                None => continue,
+            };
+            match &min_element {
+                Some(min) if min.0 == curr_element.0 => {
+                    // Advanced older iterators that match current minimum in order to resolve duplicates.
+                    iter.next();
+                }
+                Some(min) if min.0 < curr_element.0 => {}
+                _ => {
+                    min_element = Some(curr_element.to_owned());
+                    min_iter_index = Some(i);
+                }
+            }
+        }
+        if let Some(i) = min_iter_index {
+            // Advanced the minimum iterator to make progress
+            self.iterators[i].next();
+        }
+        
+        min_element.map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(entries: &[(&str, Option<&str>)]) -> std::vec::IntoIter<Result<Entry, Error>> {
+        entries.iter()
+            .map(|(k, v)| Ok((k.as_bytes().to_vec(), v.map(|v| v.as_bytes().to_vec()))))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn collect(iterator: impl Iterator<Item = Result<Entry, Error>>) -> Vec<Entry> {
+        iterator.collect::<Result<Vec<_>, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_merge() {
+        let oldest = entries(&[("a", Some("0")), ("b", Some("0")), ("d", Some("0"))]);
+        let middle = entries(&[("b", Some("1")), ("c", Some("1"))]);
+        let newest = entries(&[("a", None), ("c", Some("2")), ("e", Some("2"))]);
+
+        let merged = collect(MergeIterator::new(vec![oldest, middle, newest]));
+
+        assert_eq!(merged, collect(entries(&[
+            ("a", None),
+            ("b", Some("1")),
+            ("c", Some("2")),
+            ("d", Some("0")),
+            ("e", Some("2")),
+        ])));
+    }
+
+    #[test]
+    fn test_merge_empty() {
+        let merged = MergeIterator::new(vec![entries(&[]), entries(&[])]);
+        assert_eq!(merged.count(), 0);
+
+        let merged = MergeIterator::<std::vec::IntoIter<Result<Entry, Error>>>::new(vec![]);
+        assert_eq!(merged.count(), 0);
+    }
+
+    #[test]
+    fn test_merge_error() {
+        let failing = vec![Ok((b"a".to_vec(), None)), Err(Error::Corruption("bad block".to_string()))].into_iter();
+        let mut merged = MergeIterator::new(vec![failing, entries(&[("b", Some("1"))])]);
+
+        assert_eq!(merged.next().unwrap().unwrap(), (b"a".to_vec(), None));
+        assert!(matches!(merged.next(), Some(Err(Error::Corruption(_)))), "Error should be passed on before later keys");
+    }
+}
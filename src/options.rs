@@ -0,0 +1,246 @@
+use std::time::Duration;
+
+use crate::compression::Compression;
+
+// Controls how new segments are written. Segments already on disk keep the layout they were written with. Set like
+// `DatabaseOptions`, through a chain of calls on the defaults.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentOptions {
+    pub(crate) compression: Compression,
+    pub(crate) block_bytes: usize,
+    pub(crate) bloom_bits_per_key: usize,
+}
+
+impl Default for SegmentOptions {
+    fn default() -> SegmentOptions {
+        SegmentOptions {
+            compression: Compression::default(),
+            block_bytes: 10_000,
+            bloom_bits_per_key: 10,
+        }
+    }
+}
+
+impl SegmentOptions {
+    pub fn compression(mut self, compression: Compression) -> SegmentOptions {
+        self.compression = compression;
+        self
+    }
+
+    // Bytes of keys and values gathered before a block is closed. Larger blocks compress better, but every read
+    // decodes a whole block.
+    pub fn block_bytes(mut self, block_bytes: usize) -> SegmentOptions {
+        self.block_bytes = block_bytes;
+        self
+    }
+
+    // Bloom filter bits spent per key. More bits means fewer wasted block reads for missing keys, and 0 disables the filter.
+    pub fn bloom_bits_per_key(mut self, bloom_bits_per_key: usize) -> SegmentOptions {
+        self.bloom_bits_per_key = bloom_bits_per_key;
+        self
+    }
+}
+
+// When the write-ahead log is forced to disk. Writes which were not synced survive the process dying, but can be lost
+// on power failure.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // Sync before every write returns.
+    Always,
+    // Sync once this long has passed since the last sync. A background thread syncs writes left waiting when the log goes quiet.
+    Interval(Duration),
+    // Sync once this many bytes have been logged since the last sync.
+    Bytes(usize),
+    // Leave syncing to the operating system.
+    #[default]
+    Never,
+}
+
+// When segments are merged in the background. Flushed segments land in level 0, where their key ranges may overlap.
+// Every deeper level holds segments with disjoint key ranges, and a compaction merges segments from one level into the
+// segments they overlap in the next. The limits are a backstop on level 0 alone: once any of them is passed, part of it
+// is merged early whatever the strategy. Size-tiered compaction only counts the tiers which are out of size order
+// against them, as it keeps a few segments in every tier by design. Set like `DatabaseOptions`, through a chain of
+// calls on the defaults.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactionOptions {
+    pub(crate) strategy: CompactionStrategy,
+    pub(crate) max_segments: usize,
+    pub(crate) max_unmerged_bytes: u64,
+    pub(crate) max_read_amplification: usize,
+    pub(crate) level0_segments: usize,
+    pub(crate) level1_bytes: u64,
+    pub(crate) level_size_ratio: u64,
+    pub(crate) segment_bytes: u64,
+    pub(crate) tier_segments: usize,
+}
+
+impl Default for CompactionOptions {
+    fn default() -> CompactionOptions {
+        CompactionOptions {
+            strategy: CompactionStrategy::default(),
+            max_segments: 8,
+            max_unmerged_bytes: 64 * 1024 * 1024,
+            max_read_amplification: 4,
+            level0_segments: 4,
+            level1_bytes: 64 * 1024 * 1024,
+            level_size_ratio: 10,
+            segment_bytes: 8 * 1024 * 1024,
+            tier_segments: 4,
+        }
+    }
+}
+
+impl CompactionOptions {
+    pub fn strategy(mut self, strategy: CompactionStrategy) -> CompactionOptions {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn max_segments(mut self, max_segments: usize) -> CompactionOptions {
+        self.max_segments = max_segments;
+        self
+    }
+
+    // Most bytes held by level 0 segments newer than its oldest, which is where repeated writes of a key pile up.
+    pub fn max_unmerged_bytes(mut self, max_unmerged_bytes: u64) -> CompactionOptions {
+        self.max_unmerged_bytes = max_unmerged_bytes;
+        self
+    }
+
+    // Most level 0 segments whose key range covers any one key, and so may all be read by a single lookup.
+    pub fn max_read_amplification(mut self, max_read_amplification: usize) -> CompactionOptions {
+        self.max_read_amplification = max_read_amplification;
+        self
+    }
+
+    // Level 0 is merged into level 1 once it holds this many segments. Each is read by a lookup which misses memory.
+    pub fn level0_segments(mut self, level0_segments: usize) -> CompactionOptions {
+        self.level0_segments = level0_segments;
+        self
+    }
+
+    // Most bytes held by level 1 before a segment is pushed into level 2.
+    pub fn level1_bytes(mut self, level1_bytes: u64) -> CompactionOptions {
+        self.level1_bytes = level1_bytes;
+        self
+    }
+
+    // How many times more bytes each level from level 2 down holds than the one above it.
+    pub fn level_size_ratio(mut self, level_size_ratio: u64) -> CompactionOptions {
+        self.level_size_ratio = level_size_ratio;
+        self
+    }
+
+    // Rough size of the segments a compaction writes, so later compactions only rewrite a narrow key range.
+    pub fn segment_bytes(mut self, segment_bytes: u64) -> CompactionOptions {
+        self.segment_bytes = segment_bytes;
+        self
+    }
+
+    // Size-tiered compaction merges a run of this many segments of similar size.
+    pub fn tier_segments(mut self, tier_segments: usize) -> CompactionOptions {
+        self.tier_segments = tier_segments;
+        self
+    }
+}
+
+// How segments are picked for merging.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompactionStrategy {
+    // Push segments down through levels of disjoint key ranges. Keeps few segments to read, at the cost of rewriting
+    // each key once per level.
+    #[default]
+    Leveled,
+    // Merge segments of similar size in level 0, so each key is rewritten far less often under heavy writes, at the
+    // cost of more overlapping segments to read. Deeper levels are left as they are.
+    SizeTiered,
+}
+
+// Everything which can be tuned when a database is opened, set through a chain of calls on the defaults:
+// `DatabaseOptions::default().memtable_bytes(1 << 20).sync_policy(SyncPolicy::Always)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatabaseOptions {
+    pub(crate) memtable_bytes: usize,
+    pub(crate) log_file: String,
+    pub(crate) segment: SegmentOptions,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) compaction: CompactionOptions,
+    pub(crate) create_if_missing: bool,
+    pub(crate) error_if_exists: bool,
+    pub(crate) read_only: bool,
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> DatabaseOptions {
+        DatabaseOptions {
+            memtable_bytes: 100_000,
+            log_file: "write.log".to_string(),
+            segment: SegmentOptions::default(),
+            sync_policy: SyncPolicy::default(),
+            compaction: CompactionOptions::default(),
+            create_if_missing: true,
+            error_if_exists: false,
+            read_only: false,
+        }
+    }
+}
+
+impl DatabaseOptions {
+    // Bytes of keys and values the memtable holds before it is written out as a segment.
+    pub fn memtable_bytes(mut self, memtable_bytes: usize) -> DatabaseOptions {
+        self.memtable_bytes = memtable_bytes;
+        self
+    }
+
+    // Name of the write-ahead log within the database directory. A database has to be reopened with the name it was
+    // created with, or the writes still in its log are not found.
+    pub fn log_file(mut self, log_file: impl Into<String>) -> DatabaseOptions {
+        self.log_file = log_file.into();
+        self
+    }
+
+    pub fn block_bytes(mut self, block_bytes: usize) -> DatabaseOptions {
+        self.segment.block_bytes = block_bytes;
+        self
+    }
+
+    // Codec for new segment blocks. Blocks already on disk keep the codec they were written with.
+    pub fn compression(mut self, compression: Compression) -> DatabaseOptions {
+        self.segment.compression = compression;
+        self
+    }
+
+    pub fn bloom_bits_per_key(mut self, bloom_bits_per_key: usize) -> DatabaseOptions {
+        self.segment.bloom_bits_per_key = bloom_bits_per_key;
+        self
+    }
+
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> DatabaseOptions {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    pub fn compaction(mut self, compaction: CompactionOptions) -> DatabaseOptions {
+        self.compaction = compaction;
+        self
+    }
+
+    // Whether to start an empty database when the directory holds none. On by default.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> DatabaseOptions {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    // Whether to refuse to open a database which already exists.
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> DatabaseOptions {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    // Whether to only read. Nothing in the directory is written, truncated, compacted or deleted, and every write fails.
+    pub fn read_only(mut self, read_only: bool) -> DatabaseOptions {
+        self.read_only = read_only;
+        self
+    }
+}
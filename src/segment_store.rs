@@ -1,91 +1,185 @@
-use std::{error::Error, fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Cursor, Read, Seek, Write}, iter::Peekable, path::PathBuf};
-use std::str;
-
-use super::GetResult;
-
-use log::{debug, trace};
-
-const BLOCK_SIZE_BYTES: usize = 10_000;
+use std::{borrow::Borrow, fs::{self, File}, io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Take, Write}, ops::Bound, path::{Path, PathBuf}, sync::atomic::{AtomicBool, Ordering}};
+
+use crate::{bloom_filter::{self, BloomFilter}, checksum::crc32, compression::{compress, decompress}, cursor::EntryCursor, merge_iterator::MergeIterator, options::SegmentOptions, Entry, Error};
+
+use log::{debug, trace, warn};
+use uuid::Uuid;
+
+// Length written in place of a value's length to mark a deleted key.
+const TOMBSTONE: usize = usize::MAX;
+
+// `None` means the key is known to this segment and has been deleted.
+type EntryResult = Result<Option<Option<Vec<u8>>>, Error>;
+type BlockResult = Result<Vec<Entry>, Error>;
+type KeyRangeResult = Result<Option<(Vec<u8>, Vec<u8>)>, Error>;
+// Identifies a segment file, and the layout version it was written with.
+const MAGIC: u64 = 0x7a64_6273_6567_6d74;
+const FORMAT_VERSION: u64 = 2;
+// Segments written before the footer start with their sequence number, followed by blocks which are neither compressed
+// nor checksummed. They are told apart by not ending in the magic number.
+const LEGACY_VERSION: u64 = 0;
+// Sequence number, filter offset, index offset, filter and index checksum, format version and magic number.
+const FOOTER_SIZE: usize = 6 * 8;
+// Before blocks and the index were checksummed. Its footer has no checksum, and blocks only lead with their codec.
+const UNCHECKSUMMED_VERSION: u64 = 1;
+const UNCHECKSUMMED_FOOTER_SIZE: usize = 5 * 8;
+// Every footer ends with the format version and magic number.
+const FOOTER_TRAILER_SIZE: usize = 2 * 8;
+// The sequence number leading a legacy segment.
+const LEGACY_HEADER_SIZE: usize = 8;
+
+// A segment file is laid out as the blocks, the bloom filter, the index of each block's first key, and finally a
+// fixed size footer locating the filter and index.
 pub struct SegmentStore {
     sequence_number: usize,
     file_path: PathBuf,  
-    index: Vec<(String, usize)>, // (key, offset)
+    index: Vec<(Vec<u8>, usize)>, // (key, offset)
+    data_end: usize, // Offset just past the last block
+    filter: Option<BloomFilter>,
+    version: u64, // Layout the blocks were written with
+    obsolete: AtomicBool, // Whether to delete the file once the segment is dropped
 }
 
-pub fn load_from_file(file_path: PathBuf) -> Result<SegmentStore, Box<dyn Error>> {
+pub fn load_from_file(file_path: PathBuf) -> Result<SegmentStore, Error> {
+    let mut file = File::open(&file_path)?;
+
+    // Read in the format version from the end of the file, which decides how large the rest of the footer is
+    let file_len = file.metadata()?.len() as usize;
+    if file_len < FOOTER_TRAILER_SIZE {
+        return load_legacy(file_path, file);
+    }
+    file.seek(SeekFrom::Start((file_len - FOOTER_TRAILER_SIZE) as u64))?;
+    let mut trailer = [0u8; FOOTER_TRAILER_SIZE];
+    file.read_exact(&mut trailer)?;
+    if u64::from_ne_bytes(trailer[8..].try_into().unwrap()) != MAGIC {
+        return load_legacy(file_path, file);
+    }
+    let version = u64::from_ne_bytes(trailer[..8].try_into().unwrap());
+    let footer_size = match version {
+        FORMAT_VERSION => FOOTER_SIZE,
+        UNCHECKSUMMED_VERSION => UNCHECKSUMMED_FOOTER_SIZE,
+        _ => return Err(Error::NotSupported(format!("Segment {} has unsupported format version {}", file_path.display(), version))),
+    };
+    if file_len < footer_size {
+        return Err(Error::Corruption(format!("Segment {} is too short to hold a footer", file_path.display())));
+    }
+
+    let footer_start = file_len - footer_size;
+    file.seek(SeekFrom::Start(footer_start as u64))?;
+    let mut footer = vec![0u8; footer_size];
+    file.read_exact(&mut footer)?;
+    let field = |i: usize| u64::from_ne_bytes(footer[i * 8..(i + 1) * 8].try_into().unwrap());
+    let sequence_number = field(0) as usize;
+    let data_end = field(1) as usize;
+    let index_offset = field(2) as usize;
+    if data_end > index_offset || index_offset > footer_start {
+        return Err(Error::Corruption(format!("Segment {} has a footer pointing outside the file", file_path.display())));
+    }
+
+    // Read in the filter and index together, as they sit next to each other
+    let mut metadata = vec![0u8; footer_start - data_end];
+    file.seek(SeekFrom::Start(data_end as u64))?;
+    file.read_exact(&mut metadata)?;
+    if version == FORMAT_VERSION && crc32(&metadata) as u64 != field(3) {
+        return Err(Error::Corruption(format!("Segment {} has a mismatched filter and index checksum", file_path.display())));
+    }
+    let (filter_bytes, index_bytes) = metadata.split_at(index_offset - data_end);
+
     let mut index = Vec::new();
-    let mut reader: BufReader<File> = BufReader::new(File::open(file_path.to_owned())?);
-    let mut bytes_read = 0;
+    let mut reader = Cursor::new(index_bytes);
+    while (reader.position() as usize) < index_bytes.len() {
+        let key = decode(&mut reader)?;
+        let mut offset_bytes = [0u8; 8];
+        reader.read_exact(&mut offset_bytes)?;
+        index.push((key, usize::from_ne_bytes(offset_bytes)));
+    }
+
+    Ok(SegmentStore{
+        sequence_number,
+        file_path,
+        index,
+        data_end,
+        filter: BloomFilter::decode(filter_bytes),
+        version,
+        obsolete: AtomicBool::new(false),
+    })
+}
+
+// Rebuilds the index by reading the key in front of every block, as a legacy segment has nowhere else to keep it.
+fn load_legacy(file_path: PathBuf, mut file: File) -> Result<SegmentStore, Error> {
+    let not_a_segment = || Error::Corruption(format!("{} is not a segment file", file_path.display()));
+    let data_end = file.metadata()?.len() as usize;
+    file.rewind()?;
+    let mut reader = BufReader::new(file);
 
     // Read in the sequence number
-    let mut sequence_number_bytes: [u8; 8] = [0; 8];
-    reader.read_exact(&mut sequence_number_bytes)?;
-    bytes_read += 8;
+    let mut sequence_number_bytes = [0u8; 8];
+    reader.read_exact(&mut sequence_number_bytes).map_err(|_| not_a_segment())?;
+    let mut bytes_read = LEGACY_HEADER_SIZE;
 
     // Read each block's first key and store their offset in index
-    while !reader.fill_buf()?.is_empty() {
-        let (key, block) = read_entry(&mut reader)?;
-        index.push((str::from_utf8(key.as_slice())?.to_string(), bytes_read));
-        bytes_read += block.len();
+    let mut index = Vec::new();
+    while bytes_read < data_end {
+        let (key, block) = read_entry(&mut reader).map_err(|_| not_a_segment())?;
+        let len = 16 + key.len() + block.len();
+        index.push((key, bytes_read));
+        bytes_read += len;
     }
 
     Ok(SegmentStore{
         sequence_number: usize::from_ne_bytes(sequence_number_bytes),
-        file_path: file_path,
-        index: index,
+        file_path,
+        index,
+        data_end,
+        filter: None,
+        version: LEGACY_VERSION,
+        obsolete: AtomicBool::new(false),
     })
 }
 
-// Merges segment stores into one segment. Duplicate keys are resolved by taking the higehst sequence number key.
-pub fn compact(file_path: PathBuf, segments: &mut Vec<SegmentStore>) -> Result<SegmentStore, Box<dyn Error>> {
-    segments.sort_by(|a, b| a.get_sequence_number().cmp(&b.get_sequence_number()));
-
-    struct InterIterator {
-        iterators: Vec<Peekable<SegmentIterator>>,
-    }
-
-    impl Iterator for InterIterator {
-        type Item = (String, String);
-        
-        fn next(&mut self) -> Option<Self::Item> {
-            // Pop the current minimum key across all the segments, while resolving duplicates.
-            let mut min_element: Option<(String, String)> = None;
-            let mut min_iter_index = None;
-            // Consider segments in decreasing sequence number order
-            for i in (0..=self.iterators.len()-1).rev() {
-                let iter = &mut self.iterators[i];
-                let curr_element = iter.peek();
-                if curr_element.is_none() {
-                    continue;
-                }
-                let curr_element = curr_element.unwrap();
-                if min_element.is_none() || min_element.to_owned().unwrap().0 < curr_element.0 {
-                    min_element = Some(curr_element.to_owned());
-                    min_iter_index = Some(i);
-                    continue;
-                } else if min_element.to_owned().unwrap().0 == curr_element.0 {
-                    // Advanced iterators with lower sequence that match current minimum in order to resolve duplicates.
-                    iter.next();
-                    continue;
-                }
-            }
-            if !min_iter_index.is_none() {
-                // Advanced the minimum iterator to make progress
-                self.iterators[min_iter_index?].next();
+// Merges segment stores into new segments in the directory, starting another once one holds about `segment_bytes` of
+// keys and values. Duplicate keys are resolved by taking the higehst sequence number key. Tombstones may only be
+// dropped when no segment older than the given ones could still hold the deleted key.
+pub fn compact<S: Borrow<SegmentStore>>(directory: &Path, segments: &mut [S], drop_tombstones: bool, options: SegmentOptions, segment_bytes: u64) -> Result<Vec<SegmentStore>, Error> {
+    segments.sort_by_key(|s| s.borrow().get_sequence_number());
+
+    let sequence_number = segments.iter().map(|s| s.borrow().get_sequence_number()).min().unwrap_or(0);
+    let iterators = segments.iter().map(|s| s.borrow().iter()).collect::<Result<Vec<_>, _>>()?;
+    let mut merged = MergeIterator::new(iterators)
+        .filter(|entry| !drop_tombstones || entry.as_ref().map_or(true, |(_, v)| v.is_some()))
+        .peekable();
+
+    let mut compacted = Vec::new();
+    let mut error = None;
+    while error.is_none() && merged.peek().is_some() {
+        let mut bytes = 0;
+        let entries = std::iter::from_fn(|| {
+            if bytes >= segment_bytes {
+                return None;
             }
-            
-            min_element
-        }
+            let (k, v) = match merged.next()? {
+                Ok(entry) => entry,
+                Err(e) => {
+                    error = Some(e);
+                    return None;
+                }
+            };
+            bytes += (k.len() + v.as_ref().map_or(0, Vec::len)) as u64;
+            Some((k, v))
+        });
+        let file_path = directory.join(format!("{}.seg", Uuid::new_v4()));
+        compacted.push(SegmentStore::create_from_iterator(file_path, sequence_number, options, entries)?);
     }
 
-   SegmentStore::create_from_iterator(
-        file_path,
-        segments.iter().map(|s| s.get_sequence_number()).min().unwrap_or(0),
-        InterIterator{
-            iterators: segments.iter().map(|s| s.iter().peekable()).collect::<Vec<_>>(),
-        },
-    )
+    // An input which failed to read partway through would otherwise lose every key after the failure
+    if let Some(e) = error {
+        compacted.iter().map(|s| s.delete())
+        .filter(Result::is_err)
+        .for_each(|r| debug!("Failed to delete partial compaction output: {}", r.err().unwrap()));
+        return Err(e);
+    }
 
+    Ok(compacted)
 }
 
 impl SegmentStore {
@@ -94,103 +188,206 @@ impl SegmentStore {
         self.sequence_number
     }
     
-    pub fn iter(&self) -> SegmentIterator {
-        if self.index.len() == 0 {
-            return SegmentIterator {
-                reader: BufReader::new(self.start_from_offset(self.index[0].1).unwrap()),
-                block_iterator: BlockIterator::new(&Vec::new()),
-            };
+    // Returns the smallest and largest key held, or `None` if the segment is empty.
+    pub fn get_key_range(&self) -> KeyRangeResult {
+        let smallest = match self.index.first() {
+            Some((key, _)) => key.to_owned(),
+            None => return Ok(None),
+        };
+        let largest = match self.read_block(self.index.len() - 1)?.pop() {
+            Some((key, _)) => key,
+            None => smallest.to_owned(),
+        };
+        Ok(Some((smallest, largest)))
+    }
+
+    pub fn iter(&self) -> Result<SegmentIterator, Error> {
+        match self.version {
+            LEGACY_VERSION => self.iter_from_offset(LEGACY_HEADER_SIZE),
+            _ => self.iter_from_offset(0),
         }
+    }
 
-        SegmentIterator {
-            reader: BufReader::new(self.start_from_offset(self.index[0].1).unwrap()),
-            block_iterator: BlockIterator::new(&Vec::new()),
+    // Iterates over the keys within the range, only reading blocks from the one which could hold the start key.
+    pub fn range(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Result<impl Iterator<Item = Result<Entry, Error>>, Error> {
+        let iter = match &range.0 {
+            Bound::Included(start) | Bound::Excluded(start) => match closest_element_before(start.to_owned(), &self.index) {
+                Some((_, offset)) => self.iter_from_offset(offset)?,
+                None => self.iter()?,
+            },
+            Bound::Unbounded => self.iter()?,
+        };
 
-        }
+        // Errors are passed through, so a failed read is never mistaken for the end of the range
+        Ok(iter
+            .skip_while(move |entry| entry.as_ref().is_ok_and(|(k, _)| is_before_start(k, &range.0)))
+            .take_while(move |entry| entry.as_ref().map_or(true, |(k, _)| !is_after_end(k, &range.1))))
     }
 
-    pub fn create_from_iterator(file_path: PathBuf, sequence_number: usize, sorted_iterator: impl Iterator<Item = (String, String)>) -> Result<SegmentStore, Box<dyn Error>> {
-        let mut writer = get_writer(file_path.clone());
-        let mut bytes_written = 0usize;
+    fn read_block(&self, block: usize) -> BlockResult {
+        let mut reader = self.start_from_offset(self.index[block].1)?;
+        let (_, block) = read_entry(&mut reader)?;
+        BlockIterator::new(&block, self.version)?.collect()
+    }
+
+    // The offset may come from the index of a damaged segment, so it is checked against the end of the blocks.
+    fn iter_from_offset(&self, offset: usize) -> Result<SegmentIterator, Error> {
+        let len = self.data_end.checked_sub(offset)
+            .ok_or_else(|| Error::Corruption(format!("Segment {} has a block offset past the end of its blocks", self.get_file_name())))?;
+        Ok(SegmentIterator {
+            reader: BufReader::new(self.start_from_offset(offset)?.take(len as u64)),
+            block_iterator: BlockIterator::empty(),
+            version: self.version,
+            failed: false,
+        })
+    }
 
-        // Write out the sequence number
-        bytes_written += writer.write(&sequence_number.to_ne_bytes())?;
+    pub fn create_from_iterator(file_path: PathBuf, sequence_number: usize, options: SegmentOptions, sorted_iterator: impl Iterator<Item = (Vec<u8>, Option<Vec<u8>>)>) -> Result<SegmentStore, Error> {
+        // Written under a temporary name and only renamed once it is on disk, so a crash never leaves a partial segment
+        let temp_path = file_path.with_extension("tmp");
+        let mut writer = get_writer(&temp_path)?;
+        let mut bytes_written = 0usize;
 
         let mut index = Vec::new();
 
         let mut buffer = Vec::new();
-        let mut first_key: Option<String> = None;
+        let mut first_key: Option<Vec<u8>> = None;
+        let mut hashes = Vec::new();
 
         // Write out key value pairs into blocks which are labled with the first key in the block
         for (k, v) in sorted_iterator {
             if first_key.is_none() {
-                index.push((k.to_string(), bytes_written));
-                first_key = Some(k.to_string());
+                index.push((k.to_owned(), bytes_written));
+                first_key = Some(k.to_owned());
             }
 
-            buffer.extend(&encode(k.as_bytes())?);
-            buffer.extend(&encode(v.as_bytes())?);
+            hashes.push(bloom_filter::hash(&k));
+            buffer.extend(&encode(&k)?);
+            buffer.extend(&encode_value(v.as_deref())?);
 
-            if buffer.len() > BLOCK_SIZE_BYTES {
-                debug!("Writing block of size {} with first key \"{}\"", buffer.len(), first_key.clone().unwrap());
-                bytes_written += writer.write(encode(first_key.unwrap().as_bytes())?.as_slice())?;
-                bytes_written += writer.write(encode(compress(&buffer))?.as_slice())?;
+            if buffer.len() > options.block_bytes {
+                debug!("Writing block of size {} with first key \"{}\"", buffer.len(), String::from_utf8_lossy(first_key.as_ref().unwrap()));
+                bytes_written += write_counted(&mut writer, encode(&first_key.unwrap())?.as_slice())?;
+                bytes_written += write_counted(&mut writer, encode(&checksum_block(&compress(options.compression, &buffer)))?.as_slice())?;
                 
                 buffer.clear();
                 first_key = None;
             }
         }
 
-        if buffer.len() > 0 {
-            debug!("Writing final block of size {} with first key \"{}\"", buffer.len(), first_key.clone().unwrap());
-            bytes_written += writer.write(encode(first_key.unwrap().as_bytes())?.as_slice())?;
-            bytes_written += writer.write(encode(compress(&buffer))?.as_slice())?;
+        if !buffer.is_empty() {
+            debug!("Writing final block of size {} with first key \"{}\"", buffer.len(), String::from_utf8_lossy(first_key.as_ref().unwrap()));
+            bytes_written += write_counted(&mut writer, encode(&first_key.unwrap())?.as_slice())?;
+            bytes_written += write_counted(&mut writer, encode(&checksum_block(&compress(options.compression, &buffer)))?.as_slice())?;
+        }
+
+        // Write out the bloom filter over every key
+        let data_end = bytes_written;
+        let filter = match options.bloom_bits_per_key {
+            0 => None,
+            bits_per_key => Some(BloomFilter::from_hashes(&hashes, bits_per_key)),
+        };
+        if let Some(filter) = &filter {
+            bytes_written += write_counted(&mut writer, &filter.encode())?;
+        }
+
+        // Write out the index, followed by the footer locating everything after the blocks
+        let index_offset = bytes_written;
+        let mut metadata = filter.as_ref().map(BloomFilter::encode).unwrap_or_default();
+        for (key, offset) in index.iter() {
+            let mut entry = encode(key)?;
+            entry.extend(offset.to_ne_bytes());
+            bytes_written += write_counted(&mut writer, &entry)?;
+            metadata.extend(entry);
         }
+        for field in [sequence_number, data_end, index_offset, crc32(&metadata) as usize] {
+            bytes_written += write_counted(&mut writer, &field.to_ne_bytes())?;
+        }
+        bytes_written += write_counted(&mut writer, &FORMAT_VERSION.to_ne_bytes())?;
+        bytes_written += write_counted(&mut writer, &MAGIC.to_ne_bytes())?;
+
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp_path, &file_path)?;
+        sync_directory(&file_path)?;
 
-        debug!("Finished writing segment to {}. Wrote {} bytes in {} blocks", file_path.to_str().unwrap(), bytes_written, index.len());
+        debug!("Finished writing segment to {}. Wrote {} bytes in {} blocks", file_path.display(), bytes_written, index.len());
 
         Ok(SegmentStore{
-            sequence_number: sequence_number,
-            file_path: file_path,
-            index: index,
+            sequence_number,
+            file_path,
+            index,
+            data_end,
+            filter,
+            version: FORMAT_VERSION,
+            obsolete: AtomicBool::new(false),
         })
     }
 
+    pub fn get_size(&self) -> io::Result<u64> {
+        Ok(fs::metadata(&self.file_path)?.len())
+    }
+
+    pub fn get_file_name(&self) -> &str {
+        self.file_path.file_name().and_then(|n| n.to_str()).unwrap_or_default()
+    }
+
     pub fn delete(&self) -> io::Result<()> {
-        fs::remove_file(self.file_path.to_owned())
+        fs::remove_file(&self.file_path)
+    }
+
+    // Deletes the file once the segment is dropped, so readers which still hold it can finish with it first.
+    pub fn delete_when_unused(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for SegmentStore {
+    fn drop(&mut self) {
+        if *self.obsolete.get_mut() {
+            if let Err(e) = self.delete() {
+                warn!("Failed to delete segment {}: {}", self.file_path.display(), e);
+            }
+        }
     }
 }
 
 impl SegmentStore {
 
-    pub fn get(&self, key: &str) -> GetResult {
+    // Returns `Some(None)` if this segment holds a tombstone for the key.
+    pub fn get(&self, key: &[u8]) -> EntryResult {
+        // Skip the disk read entirely when the filter rules the key out
+        if self.filter.as_ref().is_some_and(|f| !f.may_contain(key)) {
+            trace!("Bloom filter excludes \"{}\" from segment {}", String::from_utf8_lossy(key), self.file_path.display());
+            return Ok(None);
+        }
+
         // Only scan the block which could contain the desired key value pair
-        let key = key.to_string();
+        let key = key.to_vec();
 
         let (block_key, offset) = match closest_element_before(key.clone(), &self.index) {
             Some(k) => k,
             None => return Ok(None),
         };
 
-        debug!("Nearest key for \"{}\" is \"{}\" in segment {}", key, block_key, self.file_path.to_str().unwrap());
-        debug!("Reading from offset {} in {}", offset, self.file_path.to_str().unwrap());
+        debug!("Nearest key for \"{}\" is \"{}\" in segment {}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&block_key), self.file_path.display());
+        debug!("Reading from offset {} in {}", offset, self.file_path.display());
 
         let mut reader = self.start_from_offset(offset)?;
         let (_, block) = read_entry(&mut reader)?;
 
         trace!("Block size: {}", block.len());
 
-       for (k, v) in BlockIterator::new(&block) {
-        if k == key {
-            return Ok(Some(v))
+        for entry in BlockIterator::new(&block, self.version)? {
+            let (k, v) = entry?;
+            if k == key {
+                return Ok(Some(v))
+            }
         }
-
-       }
         Ok(None)
     }
 
     fn start_from_offset(&self, offset: usize) -> io::Result<File> {
-        let mut file: File = File::open(self.file_path.to_owned())?;
+        let mut file: File = File::open(&self.file_path)?;
         file.seek(std::io::SeekFrom::Start(offset as u64))?;
         Ok(file)
     }
@@ -204,53 +401,169 @@ pub struct BlockIterator {
 
 impl BlockIterator {
     
-    pub fn new(block: &Vec<u8>) -> BlockIterator{
-        let data = decompress(&block);
-        let reader = Cursor::new(Vec::from(data));
+    pub fn new(block: &[u8], version: u64) -> Result<BlockIterator, Error> {
+        let data = match version {
+            LEGACY_VERSION => block.to_vec(),
+            UNCHECKSUMMED_VERSION => decompress(block)?,
+            _ => decompress(verify_block(block)?)?,
+        };
+        let reader = Cursor::new(data);
+
+        Ok(BlockIterator {
+            reader,
+        })
+    }
 
+    fn empty() -> BlockIterator {
         BlockIterator {
-            reader: reader,
+            reader: Cursor::new(Vec::new()),
         }
     }
 }
 
 impl Iterator for BlockIterator {
-    type Item = (String, String);
+    type Item = Result<Entry, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.reader.fill_buf().unwrap().is_empty() {
+        if self.reader.position() as usize >= self.reader.get_ref().len() {
             return None;
         }
 
-        let (k, v) = read_entry(&mut self.reader).unwrap();
-        Some((str::from_utf8(k.as_slice()).unwrap().to_string(), str::from_utf8(v.as_slice()).unwrap().to_string()))
+        let entry = decode(&mut self.reader).and_then(|k| Ok((k, decode_value(&mut self.reader)?)));
+        if entry.is_err() {
+            // Nothing after a malformed entry can be located
+            self.reader.set_position(self.reader.get_ref().len() as u64);
+        }
+        Some(entry)
     }
 }
 
 pub struct SegmentIterator {
-    reader: BufReader<File>,
+    reader: BufReader<Take<File>>, // Limited to the blocks
     block_iterator: BlockIterator,
+    version: u64,
+    failed: bool, // Stops the iterator after it has reported an error
 }
 
 impl Iterator for SegmentIterator {
-    type Item = (String, String);
+    type Item = Result<Entry, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.block_iterator.next() {
+                return Some(entry);
+            }
+            if self.failed {
+                return None;
+            }
 
-        match self.block_iterator.next() {
-            Some(x) => Some(x),
-            None => {
-                if self.reader.fill_buf().unwrap().is_empty() {
-                    return None;
+            let block = match self.reader.fill_buf() {
+                Ok([]) => return None,
+                Ok(_) => read_entry(&mut self.reader).and_then(|(_, block)| BlockIterator::new(&block, self.version)),
+                Err(e) => Err(e.into()),
+            };
+            match block {
+                Ok(block_iterator) => self.block_iterator = block_iterator,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
                 }
-                let (_, block) = read_entry(&mut self.reader).expect("Failed to reach segment during iteration!");
-                self.block_iterator = BlockIterator::new(&block);
-                self.block_iterator.next()
             }
         }
     }
 }
 
+// Keeps a single decoded block in memory and moves across block boundaries using the index.
+pub struct SegmentCursor<S: Borrow<SegmentStore>> {
+    segment: S,
+    block: usize,
+    entries: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    position: Option<usize>,
+}
+
+impl<S: Borrow<SegmentStore>> SegmentCursor<S> {
+    pub fn new(segment: S) -> SegmentCursor<S> {
+        SegmentCursor {
+            segment,
+            block: 0,
+            entries: Vec::new(),
+            position: None,
+        }
+    }
+
+    fn load_block(&mut self, block: usize) -> Result<(), Error> {
+        self.entries = self.segment.borrow().read_block(block)?;
+        self.block = block;
+        Ok(())
+    }
+
+    // Positions at the entry within the loaded block, spilling over into the start of the next block.
+    fn settle_forward(&mut self, position: usize) -> Result<(), Error> {
+        if position < self.entries.len() {
+            self.position = Some(position);
+        } else if self.block + 1 < self.segment.borrow().index.len() {
+            self.load_block(self.block + 1)?;
+            self.position = Some(0);
+        } else {
+            self.position = None;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Borrow<SegmentStore>> EntryCursor for SegmentCursor<S> {
+    fn seek(&mut self, key: &[u8]) -> Result<(), Error> {
+        let index = &self.segment.borrow().index;
+        if index.is_empty() {
+            self.position = None;
+            return Ok(());
+        }
+
+        let block = index.partition_point(|(k, _)| k.as_slice() <= key).saturating_sub(1);
+        self.load_block(block)?;
+        self.settle_forward(self.entries.partition_point(|(k, _)| k.as_slice() < key))
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<(), Error> {
+        let block = self.segment.borrow().index.partition_point(|(k, _)| k.as_slice() <= key);
+        if block == 0 {
+            // Every block starts after the key
+            self.position = None;
+            return Ok(());
+        }
+
+        self.load_block(block - 1)?;
+        self.position = self.entries.partition_point(|(k, _)| k.as_slice() <= key).checked_sub(1);
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<(), Error> {
+        match self.position {
+            Some(position) => self.settle_forward(position + 1),
+            None => Ok(()),
+        }
+    }
+
+    fn prev(&mut self) -> Result<(), Error> {
+        match self.position {
+            Some(position) if position > 0 => self.position = Some(position - 1),
+            Some(_) if self.block > 0 => {
+                self.load_block(self.block - 1)?;
+                self.position = self.entries.len().checked_sub(1);
+            }
+            _ => self.position = None,
+        }
+        Ok(())
+    }
+
+    fn entry(&self) -> Option<(&[u8], Option<&[u8]>)> {
+        self.position.map(|p| {
+            let (k, v) = &self.entries[p];
+            (k.as_slice(), v.as_deref())
+        })
+    }
+}
+
 fn closest_element_before<K:PartialOrd + Clone, V: Clone> (key: K, elements: &Vec<(K,V)>) -> Option<(K,V)> {
     if elements.len() == 0 {
         return None;
@@ -287,26 +600,88 @@ fn closest_element_before<K:PartialOrd + Clone, V: Clone> (key: K, elements: &Ve
     return Some(elements[mid].clone());
 }
 
-fn read_entry(reader: &mut impl Read) -> Result<(Vec<u8>, Vec<u8>,), Box<dyn Error>> {
+fn is_before_start(key: &[u8], start: &Bound<Vec<u8>>) -> bool {
+    match start {
+        Bound::Included(start) => key < start.as_slice(),
+        Bound::Excluded(start) => key <= start.as_slice(),
+        Bound::Unbounded => false,
+    }
+}
+
+fn is_after_end(key: &[u8], end: &Bound<Vec<u8>>) -> bool {
+    match end {
+        Bound::Included(end) => key > end.as_slice(),
+        Bound::Excluded(end) => key >= end.as_slice(),
+        Bound::Unbounded => false,
+    }
+}
+
+fn read_entry(reader: &mut impl Read) -> Result<(Vec<u8>, Vec<u8>,), Error> {
     let key = decode(reader)?;
     let value = decode(reader)?;
 
     Ok((key, value))
 }
 
-fn decode(reader: &mut impl Read) -> Result<Vec<u8>, Box<dyn Error>> {
+fn decode(reader: &mut impl Read) -> Result<Vec<u8>, Error> {
     let mut buffer = [0u8; 8];
     reader.read_exact(&mut buffer)?;
 
     let len = usize::from_ne_bytes(buffer);
     debug!("Reading string of length {}", len);
-    let mut buffer = vec![0u8; len];
-    reader.read_exact(&mut buffer)?;
+    read_exact_len(reader, len)
+}
+
+// Reads without allocating the length up front, so a corrupt length fails instead of exhausting memory.
+fn read_exact_len(reader: &mut impl Read, len: usize) -> Result<Vec<u8>, Error> {
+    let mut buffer = Vec::new();
+    reader.take(len as u64).read_to_end(&mut buffer)?;
+    if buffer.len() != len {
+        return Err(Error::Corruption(format!("Expected {} bytes but the segment ended after {}", len, buffer.len())));
+    }
 
     Ok(buffer)
 }
 
-fn encode(input_string: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+// Prefixes the block with the checksum of its contents.
+fn checksum_block(block: &[u8]) -> Vec<u8> {
+    let mut output = crc32(block).to_ne_bytes().to_vec();
+    output.extend_from_slice(block);
+    output
+}
+
+fn verify_block(block: &[u8]) -> Result<&[u8], Error> {
+    if block.len() < 4 {
+        return Err(Error::Corruption("Block is too short to hold its checksum".to_string()));
+    }
+    let (checksum, data) = block.split_at(4);
+    if u32::from_ne_bytes(checksum.try_into().unwrap()) != crc32(data) {
+        return Err(Error::Corruption("Block does not match its checksum".to_string()));
+    }
+
+    Ok(data)
+}
+
+fn decode_value(reader: &mut impl Read) -> Result<Option<Vec<u8>>, Error> {
+    let mut buffer = [0u8; 8];
+    reader.read_exact(&mut buffer)?;
+
+    let len = usize::from_ne_bytes(buffer);
+    if len == TOMBSTONE {
+        return Ok(None);
+    }
+
+    Ok(Some(read_exact_len(reader, len)?))
+}
+
+fn encode_value(value: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+    match value {
+        Some(value) => encode(value),
+        None => Ok(TOMBSTONE.to_ne_bytes().to_vec()),
+    }
+}
+
+fn encode(input_string: &[u8]) -> Result<Vec<u8>, Error> {
     let mut entry = Vec::new();
 
     let len_bytes: [u8; 8] = input_string.len().to_ne_bytes();
@@ -317,23 +692,22 @@ fn encode(input_string: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
     Ok(entry)
 }
 
-fn get_writer(file_path: PathBuf) -> File {
-    OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(file_path)
-        .unwrap()
+fn get_writer(file_path: &Path) -> io::Result<BufWriter<File>> {
+    Ok(BufWriter::new(File::create(file_path)?))
 }
 
-fn compress(input: &[u8]) -> &[u8] {
-    //TODO: Utilize a compression algorithm
-    input
+fn write_counted(writer: &mut impl Write, bytes: &[u8]) -> io::Result<usize> {
+    writer.write_all(bytes)?;
+    Ok(bytes.len())
 }
 
-fn decompress(input: &[u8]) -> &[u8] {
-    //TODO: Utilize a decompression algorithm
-    input
+// Makes a rename or removal of the file durable, by syncing the directory which holds it.
+pub fn sync_directory(file_path: &Path) -> io::Result<()> {
+    let directory = match file_path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()
 }
 
 #[cfg(test)]
@@ -341,7 +715,8 @@ mod tests {
     use std::{collections::BTreeMap, fs};
 
     use super::*;
-    use rand::{distributions::Alphanumeric, Rng}; // 0.8
+    use crate::compression::Compression;
+    use rand::Rng; // 0.8
 
     #[test]
     fn test_closest_element_before() {
@@ -360,12 +735,12 @@ mod tests {
 
     #[test]
     fn test_encode_decode() {
-        let input = sample_string(1,1000);
-        let encoded = encode(input.as_bytes()).unwrap();
+        let input = sample_bytes(1,1000);
+        let encoded = encode(&input).unwrap();
         let mut reader: BufReader<&[u8]> = BufReader::new(encoded.as_slice());
         let decoded = decode(&mut reader).unwrap();
 
-        assert_eq!(input, str::from_utf8(decoded.as_slice()).unwrap());
+        assert_eq!(input, decoded);
     }
 
     #[test]
@@ -382,7 +757,8 @@ mod tests {
         let segment_0 = SegmentStore::create_from_iterator(
             file_path_0.to_owned(), 
             0,
-            state_0.iter().map(|(k, v)| (k.to_string(), v.to_string())))
+            SegmentOptions::default(),
+            state_0.iter().map(|(k, v)| (k.as_bytes().to_vec(), Some(v.as_bytes().to_vec()))))
             .expect("Failed to create first segment!");
 
 
@@ -390,29 +766,320 @@ mod tests {
         let segment_1 = SegmentStore::create_from_iterator(
             file_path_1.to_owned(), 
             1,
-            state_1.iter().map(|(k, v)| (k.to_string(), v.to_string())))
+            SegmentOptions::default(),
+            state_1.iter().map(|(k, v)| (k.as_bytes().to_vec(), Some(v.as_bytes().to_vec()))))
             .expect("Failed to create second segment!");
 
 
-        let file_path_compact: PathBuf = PathBuf::from("temp_compact_compacted.seg");
-        let compact_segment = compact(
-            file_path_compact.to_owned(),
-            &mut vec!(segment_0, segment_1)
-        ).expect("Failed to compact segments!");
+        let mut segments = [segment_0, segment_1];
+        let compacted = compact(Path::new("."), &mut segments, true, SegmentOptions::default(), u64::MAX)
+            .expect("Failed to compact segments!");
+        assert_eq!(compacted.len(), 1);
+        let compact_segment = &compacted[0];
+
+        for (k,v) in compact_segment.iter().unwrap().map(Result::unwrap) {
+            match k.as_slice() {
+                b"a" => assert_eq!(v.unwrap(), b"1", "Latest segment should be represented!"),
+                b"b" => assert_eq!(v.unwrap(), b"0", "Keys from segment 0 are not be present!"),
+                b"c" => assert_eq!(v.unwrap(), b"1", "Keys from segment 1 are not be present!"),
+                _ => panic!("Unknown key present!"),
+            }
+        }
+
+        // Output is split into segments with disjoint key ranges once each holds enough bytes
+        let split = compact(Path::new("."), &mut segments, true, SegmentOptions::default(), 4)
+            .expect("Failed to compact segments!");
+        assert_eq!(split.iter().map(|s| s.get_key_range().unwrap().unwrap()).collect::<Vec<_>>(), vec![
+            (b"a".to_vec(), b"b".to_vec()),
+            (b"c".to_vec(), b"c".to_vec()),
+        ]);
+
         let _ = fs::remove_file(file_path_0);
         let _ = fs::remove_file(file_path_1);
+        compacted.iter().chain(split.iter()).for_each(|s| s.delete().unwrap());
 
-        for (k,v) in compact_segment.iter() {
-            match k.as_str() {
-                "a" => assert_eq!(v, "1", "Latest segment should be represented!"),
-                "b" => assert_eq!(v, "0", "Keys from segment 0 are not be present!"),
-                "c" => assert_eq!(v, "1", "Keys from segment 1 are not be present!"),
-                _ => panic!("Unknown key present!"),
+    }
+
+    #[test]
+    fn test_compact_tombstones() {
+        let state_0 = [("a", Some("0")), ("b", Some("0")), ("c", Some("0"))];
+        let state_1 = [("a", None), ("c", Some("1"))];
+
+        let file_path_0: PathBuf = PathBuf::from("temp_tombstone_0.seg");
+        let segment_0 = SegmentStore::create_from_iterator(
+            file_path_0.to_owned(),
+            0,
+            SegmentOptions::default(),
+            state_0.iter().map(|(k, v)| (k.as_bytes().to_vec(), v.map(|v| v.as_bytes().to_vec()))))
+            .expect("Failed to create first segment!");
+
+        let file_path_1: PathBuf = PathBuf::from("temp_tombstone_1.seg");
+        let segment_1 = SegmentStore::create_from_iterator(
+            file_path_1.to_owned(),
+            1,
+            SegmentOptions::default(),
+            state_1.iter().map(|(k, v)| (k.as_bytes().to_vec(), v.map(|v| v.as_bytes().to_vec()))))
+            .expect("Failed to create second segment!");
+
+        assert_eq!(segment_1.get(b"a").unwrap(), Some(None), "Tombstone should be persisted in the segment");
+        assert_eq!(load_from_file(file_path_1.to_owned()).unwrap().get(b"a").unwrap(), Some(None), "Tombstone should survive reloading the segment");
+
+        let mut segments = [segment_0, segment_1];
+
+        let kept = compact(Path::new("."), &mut segments, false, SegmentOptions::default(), u64::MAX).expect("Failed to compact segments!").remove(0);
+        assert_eq!(kept.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), vec![
+            (b"a".to_vec(), None),
+            (b"b".to_vec(), Some(b"0".to_vec())),
+            (b"c".to_vec(), Some(b"1".to_vec())),
+        ]);
+
+        let dropped = compact(Path::new("."), &mut segments, true, SegmentOptions::default(), u64::MAX).expect("Failed to compact segments!").remove(0);
+        assert_eq!(dropped.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), vec![
+            (b"b".to_vec(), Some(b"0".to_vec())),
+            (b"c".to_vec(), Some(b"1".to_vec())),
+        ]);
+        assert_eq!(dropped.get(b"a").unwrap(), None, "Dropped tombstone should leave no entry behind");
+
+        let _ = fs::remove_file(file_path_0);
+        let _ = fs::remove_file(file_path_1);
+        kept.delete().unwrap();
+        dropped.delete().unwrap();
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let state = random_state(1_000);
+
+        let file_path: PathBuf = PathBuf::from("temp_bloom_filter.seg");
+        let segment = SegmentStore::create_from_iterator(
+            file_path.to_owned(),
+            0,
+            SegmentOptions::default(),
+            state.iter().map(|(k, v)| (k.to_owned(), Some(v.to_owned()))))
+            .expect("Failed to create segment!");
+        let reloaded = load_from_file(file_path.to_owned()).expect("Failed to load segment!");
+
+        for segment in [&segment, &reloaded] {
+            let filter = segment.filter.as_ref().expect("Segment should have a bloom filter");
+            assert!(state.keys().all(|k| filter.may_contain(k)), "Bloom filter must hold every key");
+            let rejected = (0..1_000).filter(|i| !filter.may_contain(format!("missing_{}", i).as_bytes())).count();
+            assert!(rejected > 900, "Bloom filter only rejected {} absent keys", rejected);
+        }
+        assert_eq!(reloaded.iter().unwrap().map(Result::unwrap).count(), state.len(), "Iteration should stop before the bloom filter");
+
+        let file_path_unfiltered: PathBuf = PathBuf::from("temp_bloom_filter_disabled.seg");
+        let options = SegmentOptions { bloom_bits_per_key: 0, ..SegmentOptions::default() };
+        SegmentStore::create_from_iterator(
+            file_path_unfiltered.to_owned(),
+            0,
+            options,
+            state.iter().map(|(k, v)| (k.to_owned(), Some(v.to_owned()))))
+            .expect("Failed to create segment!");
+        let unfiltered = load_from_file(file_path_unfiltered.to_owned()).expect("Failed to load segment!");
+        assert!(unfiltered.filter.is_none());
+        for (k, v) in state.iter() {
+            assert_eq!(unfiltered.get(k).unwrap(), Some(Some(v.to_owned())));
+        }
+
+        let _ = fs::remove_file(file_path);
+        let _ = fs::remove_file(file_path_unfiltered);
+    }
+
+    #[test]
+    fn test_footer() {
+        let state = random_state(2_000);
+
+        let file_path: PathBuf = PathBuf::from("temp_footer.seg");
+        let segment = SegmentStore::create_from_iterator(
+            file_path.to_owned(),
+            7,
+            SegmentOptions::default(),
+            state.iter().map(|(k, v)| (k.to_owned(), Some(v.to_owned()))))
+            .expect("Failed to create segment!");
+        assert!(segment.index.len() > 1, "Segment should span several blocks");
+
+        let mut reloaded = load_from_file(file_path.to_owned()).expect("Failed to load segment!");
+        assert_eq!(reloaded.get_sequence_number(), 7);
+        assert_eq!(reloaded.index, segment.index, "Index should be read back from the index block");
+        assert_eq!(reloaded.data_end, segment.data_end);
+
+        // An index entry pointing past the blocks is reported rather than read from
+        let (last_key, _) = reloaded.index.pop().unwrap();
+        reloaded.index.push((last_key.to_owned(), reloaded.data_end + 1));
+        assert!(matches!(reloaded.range((Bound::Included(last_key), Bound::Unbounded)), Err(Error::Corruption(_))));
+
+        // A file which does not end in the magic number is rejected
+        let mut bytes = fs::read(&file_path).unwrap();
+        let len = bytes.len();
+        bytes[len - 1] ^= 0xff;
+        fs::write(&file_path, &bytes).unwrap();
+        assert!(load_from_file(file_path.to_owned()).is_err());
+
+        fs::write(&file_path, b"short").unwrap();
+        assert!(load_from_file(file_path.to_owned()).is_err());
+
+        let _ = fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_unchecksummed_segment() {
+        let mut state = random_state(2_000).into_iter().map(|(k, v)| (k, Some(v))).collect::<Vec<_>>();
+        state[10].1 = None;
+
+        // Laid out as before checksums: blocks only lead with their codec, and the footer has no checksum field
+        let mut bytes = Vec::new();
+        let mut index = Vec::new();
+        for block in state.chunks(500) {
+            index.push((block[0].0.to_owned(), bytes.len()));
+            let mut data = Vec::new();
+            for (k, v) in block {
+                data.extend(encode(k).unwrap());
+                data.extend(encode_value(v.as_deref()).unwrap());
             }
+            bytes.extend(encode(&block[0].0).unwrap());
+            bytes.extend(encode(&compress(Compression::Lz, &data)).unwrap());
+        }
+        let data_end = bytes.len();
+        let hashes = state.iter().map(|(k, _)| bloom_filter::hash(k)).collect::<Vec<_>>();
+        bytes.extend(BloomFilter::from_hashes(&hashes, 10).encode());
+        let index_offset = bytes.len();
+        for (key, offset) in index.iter() {
+            bytes.extend(encode(key).unwrap());
+            bytes.extend(offset.to_ne_bytes());
+        }
+        for field in [5, data_end as u64, index_offset as u64, UNCHECKSUMMED_VERSION, MAGIC] {
+            bytes.extend(field.to_ne_bytes());
+        }
+        let file_path: PathBuf = PathBuf::from("temp_unchecksummed.seg");
+        fs::write(&file_path, &bytes).unwrap();
+
+        let segment = load_from_file(file_path.to_owned()).expect("Failed to load segment!");
+        assert_eq!(segment.get_sequence_number(), 5);
+        assert_eq!(segment.index, index);
+        assert!(segment.filter.is_some());
+        for (k, v) in state.iter() {
+            assert_eq!(segment.get(k).unwrap(), Some(v.to_owned()));
         }
+        assert_eq!(segment.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), state);
+        assert_eq!(segment.get_key_range().unwrap(), Some((state[0].0.to_owned(), state[state.len() - 1].0.to_owned())));
 
-        let _ = fs::remove_file(file_path_compact);
+        let _ = fs::remove_file(file_path);
+    }
 
+    #[test]
+    fn test_block_checksum() {
+        let state = random_state(2_000);
+
+        let file_path: PathBuf = PathBuf::from("temp_block_checksum.seg");
+        let segment = SegmentStore::create_from_iterator(
+            file_path.to_owned(),
+            0,
+            SegmentOptions::default(),
+            state.iter().map(|(k, v)| (k.to_owned(), Some(v.to_owned()))))
+            .expect("Failed to create segment!");
+
+        // Flip a bit inside the second block's data
+        let mut bytes = fs::read(&file_path).unwrap();
+        let (key, offset) = segment.index[1].to_owned();
+        bytes[offset + encode(&key).unwrap().len() + 20] ^= 1;
+        fs::write(&file_path, &bytes).unwrap();
+
+        let error = segment.get(&key).unwrap_err();
+        assert!(matches!(error, Error::Corruption(_)), "Expected a corruption error but got {}", error);
+        assert!(segment.read_block(0).is_ok(), "Other blocks should still be readable");
+
+        // Iteration reports the bad block once and stops, instead of panicking
+        let entries = segment.iter().unwrap().collect::<Vec<_>>();
+        assert!(matches!(entries.last(), Some(Err(Error::Corruption(_)))));
+        assert_eq!(entries.iter().filter(|entry| entry.is_err()).count(), 1);
+        assert!(compact(Path::new("."), &mut [&segment], true, SegmentOptions::default(), u64::MAX).is_err());
+
+        // The index and filter are checked as well
+        bytes[segment.data_end + 1] ^= 1;
+        fs::write(&file_path, &bytes).unwrap();
+        let error = load_from_file(file_path.to_owned()).err().unwrap();
+        assert!(matches!(error, Error::Corruption(_)), "Expected a corruption error but got {}", error);
+
+        let _ = fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_range() {
+        let file_path: PathBuf = PathBuf::from("test_temp_range.seg");
+        let state = random_state(2_000);
+        let segment = SegmentStore::create_from_iterator(
+            file_path.to_owned(),
+            0,
+            SegmentOptions::default(),
+            state.iter().map(|(k, v)| (k.to_owned(), Some(v.to_owned())))
+        ).unwrap();
+
+        let keys = state.keys().collect::<Vec<_>>();
+        let (start, end) = (keys[keys.len() / 3].to_owned(), keys[2 * keys.len() / 3].to_owned());
+
+        let expected = state.range(start.to_owned()..end.to_owned()).map(|(k, _)| k.to_owned()).collect::<Vec<_>>();
+        let actual = segment.range((Bound::Included(start.to_owned()), Bound::Excluded(end.to_owned()))).unwrap().map(|entry| entry.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(expected, actual);
+
+        let expected = state.range((Bound::Excluded(start.to_owned()), Bound::Unbounded)).map(|(k, _)| k.to_owned()).collect::<Vec<_>>();
+        let actual = segment.range((Bound::Excluded(start.to_owned()), Bound::Unbounded)).unwrap().map(|entry| entry.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(expected, actual);
+
+        let _ = fs::remove_file(file_path);
+    }
+
+    #[test]
+    fn test_cursor() {
+        let file_path: PathBuf = PathBuf::from("test_temp_cursor.seg");
+        let state = random_state(2_000);
+        let segment = SegmentStore::create_from_iterator(
+            file_path.to_owned(),
+            0,
+            SegmentOptions::default(),
+            state.iter().map(|(k, v)| (k.to_owned(), Some(v.to_owned())))
+        ).unwrap();
+        assert!(segment.index.len() > 1, "Segment should span several blocks");
+
+        let keys = state.keys().collect::<Vec<_>>();
+        let mut cursor = SegmentCursor::new(&segment);
+
+        // Walk forward over every block from the first key
+        cursor.seek(b"").unwrap();
+        for key in keys.iter() {
+            assert_eq!(cursor.entry().unwrap().0, key.as_slice());
+            cursor.next().unwrap();
+        }
+        assert!(cursor.entry().is_none(), "Cursor should move past the last key");
+
+        // Walk backwards over every block from the last key
+        cursor.seek_for_prev(keys[keys.len() - 1]).unwrap();
+        for key in keys.iter().rev() {
+            assert_eq!(cursor.entry().unwrap().0, key.as_slice());
+            cursor.prev().unwrap();
+        }
+        assert!(cursor.entry().is_none(), "Cursor should move past the first key");
+
+        // Seeking between keys lands on the neighbouring keys
+        let mut middle = keys[keys.len() / 2].to_owned();
+        middle.push(0);
+        cursor.seek(&middle).unwrap();
+        assert_eq!(cursor.entry().unwrap().0, keys[keys.len() / 2 + 1].as_slice());
+        cursor.seek_for_prev(&middle).unwrap();
+        assert_eq!(cursor.entry().unwrap().0, keys[keys.len() / 2].as_slice());
+
+        cursor.seek_for_prev(b"").unwrap();
+        assert!(cursor.entry().is_none(), "No key sorts before the empty key");
+
+        // A cursor owning the segment keeps its file until it is done, even once the segment has been replaced
+        let segment = std::sync::Arc::new(segment);
+        let mut cursor = SegmentCursor::new(segment.to_owned());
+        segment.delete_when_unused();
+        drop(segment);
+        assert!(file_path.exists());
+        cursor.seek(keys[0]).unwrap();
+        assert_eq!(cursor.entry().unwrap().0, keys[0].as_slice());
+        drop(cursor);
+        assert!(!file_path.exists(), "Replaced segment should be deleted once unused");
     }
 
     #[test]
@@ -423,20 +1090,21 @@ mod tests {
         let segment = SegmentStore::create_from_iterator(
             file_path.to_owned(), 
             0,
-            state.iter().map(|(k, v)| (k.to_string(), v.to_string()))
+            SegmentOptions::default(),
+            state.iter().map(|(k, v)| (k.to_owned(), Some(v.to_owned())))
         
         ).unwrap();
 
         for (k, v) in state.clone() {
             let result = segment.get(&k).unwrap();
-            assert_eq!(result.unwrap(), v.to_string());
+            assert_eq!(result.unwrap().unwrap(), v);
         }
     
         let segment = load_from_file(file_path.to_owned()).unwrap();
 
         for (k, v) in state {
             let result = segment.get(&k).unwrap();
-            assert_eq!(result.unwrap(), v.to_string());
+            assert_eq!(result.unwrap().unwrap(), v);
         }
 
         let _ = fs::remove_file(file_path);
@@ -449,36 +1117,35 @@ mod tests {
         let segment = SegmentStore::create_from_iterator(
             file_path.to_owned(), 
             0,
-            state.iter().map(|(k, v)| (k.to_string(), v.to_string()))
+            SegmentOptions::default(),
+            state.iter().map(|(k, v)| (k.to_owned(), Some(v.to_owned())))
         
         ).unwrap();
 
-        assert_eq!(state.iter().count(), segment.iter().count());
+        assert_eq!(state.len(), segment.iter().unwrap().map(Result::unwrap).count());
 
-        state.iter().zip(segment.iter()).for_each(|((k1, v1), (k2, v2))| {
+        state.iter().zip(segment.iter().unwrap().map(Result::unwrap)).for_each(|((k1, v1), (k2, v2))| {
             assert_eq!(k1.to_owned(), k2);
-            assert_eq!(v1.to_owned(), v2);
+            assert_eq!(Some(v1.to_owned()), v2);
         });
 
         let _ = fs::remove_file(file_path);
     }
 
-    fn random_state(count: u32) -> BTreeMap<String, String> {
+    fn random_state(count: u32) -> BTreeMap<Vec<u8>, Vec<u8>> {
         let mut entries = BTreeMap::new();
         for _ in 0..count {
-            entries.insert(sample_string(1, 10), sample_string(1, 20));
+            entries.insert(sample_bytes(1, 10), sample_bytes(1, 20));
         }
         
         entries
     }
 
-    fn sample_string(min: usize, max: usize) -> String {        
+    // Arbitrary bytes, which are rarely valid UTF-8.
+    fn sample_bytes(min: usize, max: usize) -> Vec<u8> {        
         let mut entropy = rand::thread_rng();
         let length: usize = entropy.gen_range(min..max);
 
-        entropy.sample_iter(&Alphanumeric)
-                .take(length)
-                .map(char::from)
-                .collect()
+        (0..length).map(|_| entropy.gen()).collect()
     }
 }
\ No newline at end of file
@@ -0,0 +1,112 @@
+use std::{ops::{Bound, RangeBounds}, sync::Arc};
+
+use crate::{cursor::{DatabaseCursor, EntryCursor}, memory_store::{MemoryCursor, MemoryStore}, merge_iterator::MergeIterator, segment_store::{SegmentCursor, SegmentStore}, Entry, Error, GetResult};
+
+// A live key and its value, or the error which stopped the scan
+pub(crate) type ScanResult = Result<(Vec<u8>, Vec<u8>), Error>;
+
+// The database as it was after one write, no matter what is written after it. It holds on to the memtables and
+// segments it reads, skipping memtable versions written after its sequence number, and segments replaced by a compaction
+// are only deleted once every snapshot reading them is dropped.
+pub struct Snapshot {
+    sequence_number: u64,
+    memtables: Vec<Arc<MemoryStore>>, // Ordered from oldest to newest
+    segments: Vec<Arc<SegmentStore>>, // Ordered from oldest to newest
+}
+
+impl Snapshot {
+    pub(crate) fn new(sequence_number: u64, memtables: Vec<Arc<MemoryStore>>, segments: Vec<Arc<SegmentStore>>) -> Snapshot {
+        Snapshot { sequence_number, memtables, segments }
+    }
+
+    // Number of the last write the snapshot sees. Each write, a whole batch included, takes the next number.
+    pub fn sequence_number(&self) -> u64 {
+        self.sequence_number
+    }
+
+    pub fn get(&self, key: &[u8]) -> GetResult {
+        match self.memtables.iter().rev().find_map(|memory| memory.get_entry(key, self.sequence_number)) {
+            Some(value) => Ok(value),
+            None => get_from_segments(&self.segments, key),
+        }
+    }
+
+    // Iterates over the live key value pairs within the range in key order.
+    pub fn scan<'k>(&self, range: impl RangeBounds<&'k [u8]>) -> Result<impl Iterator<Item = ScanResult>, Error> {
+        let memtables = self.memtables.iter().map(Arc::as_ref).collect::<Vec<_>>();
+        scan_range(&memtables, self.sequence_number, &self.segments, owned_range(range))
+    }
+
+    // Iterates over the live keys starting with the prefix in key order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<impl Iterator<Item = ScanResult>, Error> {
+        let memtables = self.memtables.iter().map(Arc::as_ref).collect::<Vec<_>>();
+        scan_range(&memtables, self.sequence_number, &self.segments, prefix_range(prefix))
+    }
+
+    // Returns an unpositioned cursor, which must be placed with `seek` or `seek_for_prev` before stepping.
+    pub fn cursor(&self) -> DatabaseCursor<'static> {
+        // Children are ordered from oldest to newest so newer values shadow older ones
+        let mut children: Vec<Box<dyn EntryCursor>> = Vec::new();
+        for segment in self.segments.iter() {
+            children.push(Box::new(SegmentCursor::new(segment.to_owned())));
+        }
+        for memory in self.memtables.iter() {
+            children.push(Box::new(MemoryCursor::new(memory.to_owned(), self.sequence_number)));
+        }
+        DatabaseCursor::new(children)
+    }
+}
+
+// Looks the key up from the newest segment to the oldest, as a tombstone in a newer segment hides any value in older ones.
+pub(crate) fn get_from_segments(segments: &[Arc<SegmentStore>], key: &[u8]) -> GetResult {
+    for segment in segments.iter().rev() {
+        if let Some(value) = segment.get(key)? {
+            return Ok(value)
+        }
+    }
+    Ok(None)
+}
+
+// Merges the memtables, as they were at the sequence number, and segments, both ordered from oldest to newest, over the
+// range. Memtables are small, so their part of the range is copied out and the iterator holds on to nothing but the
+// segment files it reads.
+pub(crate) fn scan_range(memtables: &[&MemoryStore], sequence_number: u64, segments: &[Arc<SegmentStore>], range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Result<impl Iterator<Item = ScanResult>, Error> {
+    let invalid = match &range {
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start > end,
+        _ => false,
+    };
+    if invalid {
+        return Err(Error::InvalidArgument("Range start must not be after its end".to_string()));
+    }
+
+    // Sources are ordered from oldest to newest so newer values win the merge
+    let mut sources: Vec<Box<dyn Iterator<Item = Result<Entry, Error>>>> = Vec::new();
+    for segment in segments.iter() {
+        sources.push(Box::new(segment.range(range.clone())?));
+    }
+    for memory in memtables.iter() {
+        sources.push(Box::new(memory.range(range.clone(), sequence_number).into_iter().map(Ok)));
+    }
+
+    Ok(MergeIterator::new(sources).filter_map(|entry| entry.map(|(k, v)| v.map(|v| (k, v))).transpose()))
+}
+
+pub(crate) fn owned_range<'k>(range: impl RangeBounds<&'k [u8]>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (range.start_bound().map(|k| k.to_vec()), range.end_bound().map(|k| k.to_vec()))
+}
+
+// Covers exactly the keys starting with the prefix, so only those are read from the memtables. It ends before the
+// prefix with its last byte below 0xff incremented and the bytes after it dropped, and is unbounded when every byte is
+// 0xff.
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let end = match prefix.iter().rposition(|&byte| byte != 0xff) {
+        Some(last) => {
+            let mut end = prefix[..=last].to_vec();
+            end[last] += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_vec()), end)
+}
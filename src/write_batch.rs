@@ -0,0 +1,36 @@
+// A group of sets and deletes which is logged as a single record, so it is applied all-or-nothing.
+#[derive(Default)]
+pub struct WriteBatch {
+    operations: Vec<(Vec<u8>, Option<Vec<u8>>)>, // A `None` value deletes the key
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch {
+            operations: Vec::new(),
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> &mut WriteBatch {
+        self.operations.push((key.to_owned(), Some(value.to_owned())));
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut WriteBatch {
+        self.operations.push((key.to_owned(), None));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    // Operations in the order they were added, so later ones win.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &(Vec<u8>, Option<Vec<u8>>)> {
+        self.operations.iter()
+    }
+}